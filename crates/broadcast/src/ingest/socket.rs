@@ -0,0 +1,385 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+
+use async_std::channel::Receiver;
+use async_std::net::UdpSocket;
+use async_std::prelude::FutureExt;
+use ftl_protocol::protocol::FtlHandshakeFinalised;
+use ftl_protocol::server::MediaActivity;
+use ftl_protocol::signing::{PacketKey, ReplayWindow};
+use log::{debug, error, info, trace};
+use rtp::packet::Packet;
+use webrtc_util::marshal::Unmarshal;
+
+use super::stats::IngestStats;
+
+/// Large enough for any datagram an FTL client produces.
+const MAX_PACKET_SIZE: usize = 2048;
+
+#[derive(Debug, Clone)]
+struct MediaStream {
+    payload_type: u8,
+    ssrc: u32,
+    /// Only checked for signed streams.
+    replay: ReplayWindow,
+}
+
+/// Which hosts are allowed to send media to an ingest socket.
+#[derive(Debug, Clone, Copy)]
+pub enum SourceFilter {
+    /// Only accept packets from this address, normally the peer
+    /// address of the FTL control connection.
+    Pinned(IpAddr),
+    /// Accept packets from any address.
+    ///
+    /// Only use this for clients behind NATs which send UDP
+    /// from a different public address than TCP.
+    Any,
+}
+
+impl SourceFilter {
+    fn allows(&self, source: IpAddr) -> bool {
+        match self {
+            SourceFilter::Pinned(addr) => canonical(*addr) == canonical(source),
+            SourceFilter::Any => true,
+        }
+    }
+}
+
+/// Shared, updatable [`SourceFilter`] for a running ingest, see [`FtlIngest::source_handle`].
+#[derive(Debug, Clone)]
+pub struct SourceHandle(Arc<RwLock<SourceFilter>>);
+
+impl SourceHandle {
+    pub fn new(source: SourceFilter) -> SourceHandle {
+        SourceHandle(Arc::new(RwLock::new(source)))
+    }
+
+    /// Replace the filter, packets are checked against it from now on.
+    ///
+    /// Call this from `IngestServer::on_resume` with the client's new address.
+    pub fn set(&self, source: SourceFilter) {
+        *self.0.write().unwrap_or_else(|error| error.into_inner()) = source;
+    }
+
+    pub fn get(&self) -> SourceFilter {
+        *self.0.read().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Whether both handles belong to the same ingest.
+    pub fn ptr_eq(&self, other: &SourceHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Treat IPv4-mapped IPv6 addresses the same as their IPv4 counterpart,
+/// dual-stack listeners report peers in either form.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        addr => addr,
+    }
+}
+
+#[derive(Debug)]
+enum Rejection {
+    WrongSource,
+    Malformed,
+    UnknownPayloadType,
+    SsrcMismatch,
+    BadSignature,
+    Replayed,
+}
+
+/// UDP media socket for a single FTL stream.
+///
+/// Every datagram is checked against the [`SourceFilter`], then parsed as RTP
+/// and checked against the payload types and SSRCs negotiated in the handshake
+/// (and the signature and sequence number, for signed streams) before being relayed to mediasoup.
+pub struct FtlIngest {
+    socket: UdpSocket,
+    source: SourceHandle,
+    video: Option<MediaStream>,
+    audio: Option<MediaStream>,
+    stats: Arc<IngestStats>,
+    activity: Option<MediaActivity>,
+    packet_key: Option<PacketKey>,
+}
+
+impl FtlIngest {
+    /// Bind the media socket on `addr`.
+    ///
+    /// Do this before telling the client which port to use, so a port
+    /// which can't be bound is never handed out.
+    pub async fn bind(addr: SocketAddr, source: SourceFilter, handshake: &FtlHandshakeFinalised) -> io::Result<FtlIngest> {
+        let socket = UdpSocket::bind(addr).await?;
+        FtlIngest::with_socket(socket, source, handshake)
+    }
+
+    /// Use a socket which is already bound, such as one from `PortAllocator::allocate`.
+    pub fn from_std(socket: std::net::UdpSocket, source: SourceFilter, handshake: &FtlHandshakeFinalised) -> io::Result<FtlIngest> {
+        FtlIngest::with_socket(UdpSocket::from(socket), source, handshake)
+    }
+
+    fn with_socket(socket: UdpSocket, source: SourceFilter, handshake: &FtlHandshakeFinalised) -> io::Result<FtlIngest> {
+        info!("FTL ingest listening on {}.", socket.local_addr()?);
+
+        Ok(FtlIngest {
+            socket,
+            source: SourceHandle::new(source),
+            video: handshake.video.as_ref().map(|video| MediaStream {
+                payload_type: video.payload_type,
+                ssrc: video.ssrc,
+                replay: ReplayWindow::default(),
+            }),
+            audio: handshake.audio.as_ref().map(|audio| MediaStream {
+                payload_type: audio.payload_type,
+                ssrc: audio.ssrc,
+                replay: ReplayWindow::default(),
+            }),
+            stats: Arc::new(IngestStats::default()),
+            activity: None,
+            packet_key: None,
+        })
+    }
+
+    /// Report forwarded media to the control server, used for its media timeout.
+    pub fn with_activity(mut self, activity: MediaActivity) -> FtlIngest {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Drop RTP packets which aren't signed with this key, pass the key given to `allocate_ingest`.
+    pub fn with_packet_key(mut self, packet_key: PacketKey) -> FtlIngest {
+        self.packet_key = Some(packet_key);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn stats(&self) -> Arc<IngestStats> {
+        self.stats.clone()
+    }
+
+    /// Handle for changing the source filter while the ingest is running,
+    /// such as when a roaming client resumes from a new address.
+    pub fn source_handle(&self) -> SourceHandle {
+        self.source.clone()
+    }
+
+    /// Relay valid packets to `target` until the stop signal fires,
+    /// `target` should be the local address of the router's plain transport.
+    pub async fn run(mut self, target: SocketAddr, stop_signal: Receiver<()>) {
+        let relay = match FtlIngest::connect_relay(target).await {
+            Ok(relay) => relay,
+            Err(error) => {
+                error!("Failed to connect FTL ingest to router at {}. {}", target, error);
+                return;
+            }
+        };
+
+        debug!("FTL ingest relaying to {}.", target);
+        let mut buffer = [0_u8; MAX_PACKET_SIZE];
+
+        loop {
+            let received = async { Some(self.socket.recv_from(&mut buffer).await) }
+                .race(async {
+                    stop_signal.recv().await.ok();
+                    None
+                })
+                .await;
+
+            match received {
+                Some(Ok((length, source))) => self.process(&relay, &buffer[..length], source).await,
+                Some(Err(error)) => {
+                    error!("Failed to read from FTL ingest socket. {}", error);
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        debug!("FTL ingest stopped. {:?}", self.stats.snapshot());
+    }
+
+    async fn connect_relay(target: SocketAddr) -> io::Result<UdpSocket> {
+        let relay = UdpSocket::bind(SocketAddr::new(target.ip(), 0)).await?;
+        relay.connect(target).await?;
+        Ok(relay)
+    }
+
+    async fn process(&mut self, relay: &UdpSocket, data: &[u8], source: SocketAddr) {
+        IngestStats::increment(&self.stats.packets_received);
+
+        let counter = match self.inspect(data, source) {
+            Ok(()) => {
+                if let Err(error) = relay.send(data).await {
+                    error!("Failed to relay packet to router. {}", error);
+                    return;
+                }
+
+                IngestStats::increment(&self.stats.packets_forwarded);
+                if let Some(activity) = &self.activity {
+                    activity.touch();
+                }
+
+                self.stats.bytes_forwarded.fetch_add(data.len() as u64, Ordering::Relaxed);
+                return;
+            }
+            Err(Rejection::WrongSource) => &self.stats.packets_wrong_source,
+            Err(Rejection::Malformed) => &self.stats.packets_malformed,
+            Err(Rejection::UnknownPayloadType) => &self.stats.packets_unknown_payload_type,
+            Err(Rejection::SsrcMismatch) => &self.stats.packets_ssrc_mismatch,
+            Err(Rejection::BadSignature) => &self.stats.packets_bad_signature,
+            Err(Rejection::Replayed) => &self.stats.packets_replayed,
+        };
+
+        IngestStats::increment(counter);
+        trace!("Dropped packet from {}.", source);
+    }
+
+    fn inspect(&mut self, data: &[u8], source: SocketAddr) -> Result<(), Rejection> {
+        if !self.source.get().allows(source.ip()) {
+            return Err(Rejection::WrongSource);
+        }
+
+        // RTCP shares the port with RTP (RFC 5761), only check the sender SSRC.
+        if data.len() >= 8 && (192..=223).contains(&data[1]) {
+            // RTCP can't carry a signature, so on signed streams it could be anyone's.
+            if self.packet_key.is_some() {
+                return Err(Rejection::BadSignature);
+            }
+
+            let ssrc = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+            return if self.streams().any(|stream| stream.ssrc == ssrc) {
+                Ok(())
+            } else {
+                Err(Rejection::SsrcMismatch)
+            };
+        }
+
+        let packet = Packet::unmarshal(&mut &data[..])
+            .map_err(|_| Rejection::Malformed)?;
+
+        let stream = self.video.iter_mut().chain(self.audio.iter_mut())
+            .find(|stream| stream.payload_type == packet.header.payload_type)
+            .ok_or(Rejection::UnknownPayloadType)?;
+
+        if stream.ssrc != packet.header.ssrc {
+            return Err(Rejection::SsrcMismatch);
+        }
+
+        if let Some(packet_key) = &self.packet_key {
+            packet_key.verify(data).map_err(|_| Rejection::BadSignature)?;
+            stream.replay.check(packet.header.sequence_number).map_err(|_| Rejection::Replayed)?;
+        }
+
+        Ok(())
+    }
+
+    fn streams(&self) -> impl Iterator<Item = &MediaStream> {
+        self.video.iter().chain(self.audio.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use async_std::channel::bounded;
+    use async_std::future::timeout;
+    use async_std::net::UdpSocket;
+    use async_std::task;
+    use ftl_protocol::protocol::{FtlHandshakeFinalised, KnownAudio, ProtocolVersion, Vendor};
+    use ftl_protocol::signing::PacketKey;
+
+    use super::{FtlIngest, SourceFilter};
+
+    fn handshake() -> FtlHandshakeFinalised {
+        FtlHandshakeFinalised {
+            protocol_version: ProtocolVersion::new(0, 9),
+            vendor: Vendor {
+                name: None,
+                version: None,
+            },
+            video: None,
+            audio: Some(KnownAudio {
+                codec: "OPUS".to_string(),
+                payload_type: 97,
+                ssrc: 77,
+            }),
+            features: Default::default(),
+            unknown_attributes: Default::default(),
+        }
+    }
+
+    #[async_std::test]
+    async fn should_accept_media_from_resumed_address() {
+        let pinned = SourceFilter::Pinned("127.0.0.2".parse().unwrap());
+        let ingest = FtlIngest::bind("127.0.0.1:0".parse().unwrap(), pinned, &handshake()).await.unwrap();
+        let addr = ingest.local_addr().unwrap();
+        let source = ingest.source_handle();
+        let stats = ingest.stats();
+
+        let router = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (stop, stop_signal) = bounded(1);
+        let ingest = task::spawn(ingest.run(router.local_addr().unwrap(), stop_signal));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = [0x80, 97, 0, 1, 0, 0, 0, 0, 0, 0, 0, 77, b'o', b'p', b'u', b's'];
+
+        // Media from the client's new address is dropped at first,
+        client.send_to(&packet, addr).await.unwrap();
+        while stats.packets_wrong_source.load(Ordering::Relaxed) == 0 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+
+        // and relayed once the stream is resumed from there.
+        source.set(SourceFilter::Pinned(client.local_addr().unwrap().ip()));
+        client.send_to(&packet, addr).await.unwrap();
+
+        let mut buffer = [0_u8; 64];
+        let length = timeout(Duration::from_secs(1), router.recv(&mut buffer)).await.unwrap().unwrap();
+        assert_eq!(&buffer[..length], &packet[..]);
+        assert_eq!(stats.packets_wrong_source.load(Ordering::Relaxed), 1);
+
+        stop.close();
+        ingest.await;
+    }
+
+    #[async_std::test]
+    async fn should_drop_rtcp_on_signed_streams() {
+        let packet_key = PacketKey::derive("0123", "key").unwrap();
+        let ingest = FtlIngest::bind("127.0.0.1:0".parse().unwrap(), SourceFilter::Any, &handshake()).await.unwrap()
+            .with_packet_key(packet_key.clone());
+        let addr = ingest.local_addr().unwrap();
+        let stats = ingest.stats();
+
+        let router = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (stop, stop_signal) = bounded(1);
+        let ingest = task::spawn(ingest.run(router.local_addr().unwrap(), stop_signal));
+
+        // A receiver report claiming to come from the stream's SSRC.
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&[0x80, 201, 0, 1, 0, 0, 0, 77], addr).await.unwrap();
+        while stats.packets_bad_signature.load(Ordering::Relaxed) == 0 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Signed media still gets through.
+        let packet = packet_key.sign(&[0x80, 97, 0, 1, 0, 0, 0, 0, 0, 0, 0, 77, b'o', b'p', b'u', b's']).unwrap();
+        client.send_to(&packet, addr).await.unwrap();
+
+        let mut buffer = [0_u8; 64];
+        let length = timeout(Duration::from_secs(1), router.recv(&mut buffer)).await.unwrap().unwrap();
+        assert_eq!(&buffer[..length], &packet[..]);
+
+        stop.close();
+        ingest.await;
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters describing what an ingest socket has seen so far.
+#[derive(Default, Debug)]
+pub struct IngestStats {
+    pub packets_received: AtomicU64,
+    pub packets_forwarded: AtomicU64,
+    pub bytes_forwarded: AtomicU64,
+    pub packets_malformed: AtomicU64,
+    pub packets_unknown_payload_type: AtomicU64,
+    pub packets_ssrc_mismatch: AtomicU64,
+    pub packets_wrong_source: AtomicU64,
+    pub packets_bad_signature: AtomicU64,
+    pub packets_replayed: AtomicU64,
+}
+
+/// Point in time copy of [`IngestStats`].
+#[derive(Default, Debug, Clone)]
+pub struct IngestStatsSnapshot {
+    pub packets_received: u64,
+    pub packets_forwarded: u64,
+    pub bytes_forwarded: u64,
+    pub packets_malformed: u64,
+    pub packets_unknown_payload_type: u64,
+    pub packets_ssrc_mismatch: u64,
+    pub packets_wrong_source: u64,
+    pub packets_bad_signature: u64,
+    pub packets_replayed: u64,
+}
+
+impl IngestStats {
+    pub fn snapshot(&self) -> IngestStatsSnapshot {
+        IngestStatsSnapshot {
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            packets_forwarded: self.packets_forwarded.load(Ordering::Relaxed),
+            bytes_forwarded: self.bytes_forwarded.load(Ordering::Relaxed),
+            packets_malformed: self.packets_malformed.load(Ordering::Relaxed),
+            packets_unknown_payload_type: self.packets_unknown_payload_type.load(Ordering::Relaxed),
+            packets_ssrc_mismatch: self.packets_ssrc_mismatch.load(Ordering::Relaxed),
+            packets_wrong_source: self.packets_wrong_source.load(Ordering::Relaxed),
+            packets_bad_signature: self.packets_bad_signature.load(Ordering::Relaxed),
+            packets_replayed: self.packets_replayed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -5,6 +5,10 @@
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(feature = "ingest")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ingest")))]
+pub mod ingest;
+
 #[cfg(feature = "rtc")]
 #[cfg_attr(docsrs, doc(cfg(feature = "rtc")))]
 pub mod rtc;
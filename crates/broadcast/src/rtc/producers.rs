@@ -1,7 +1,7 @@
 use std::num::{NonZeroU32, NonZeroU8};
 use std::net::SocketAddr;
 
-use mediasoup::plain_transport::PlainTransportOptions;
+use mediasoup::plain_transport::{PlainTransport, PlainTransportOptions};
 use mediasoup::prelude::TransportListenIp;
 use mediasoup::producer::{Producer, ProducerOptions};
 use mediasoup::router::Router;
@@ -12,10 +12,10 @@ use crate::rtc::codecs::{AudioCodec, VideoCodec};
 
 use super::routers::DataSource;
 
-pub async fn init_producers(router: &Router, source: &DataSource, addr: SocketAddr) -> Vec<Producer> {
+pub async fn init_producers(router: &Router, source: &DataSource, addr: SocketAddr) -> (PlainTransport, Vec<Producer>) {
     let mut producers = Vec::new();
 
-    match source {
+    let transport = match source {
         DataSource::Ftl(handshake) => {
             // Prepare transport options
             let listen_ip = TransportListenIp {
@@ -23,7 +23,11 @@ pub async fn init_producers(router: &Router, source: &DataSource, addr: SocketAd
                 announced_ip: None,
             };
             let mut transport_options = PlainTransportOptions::new(listen_ip);
-            transport_options.port = Some(addr.port());
+            // Port 0 lets mediasoup pick one from the worker's range.
+            if addr.port() != 0 {
+                transport_options.port = Some(addr.port());
+            }
+
             transport_options.rtcp_mux = true;
             transport_options.comedia = true;
 
@@ -92,8 +96,10 @@ pub async fn init_producers(router: &Router, source: &DataSource, addr: SocketAd
                     ).await.unwrap()
                 );
             }
+
+            plain_transport
         }
-    }
+    };
 
-    return producers;
+    (transport, producers)
 }
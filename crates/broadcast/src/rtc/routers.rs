@@ -1,5 +1,6 @@
 use std::net::SocketAddr;
 
+use mediasoup::plain_transport::PlainTransport;
 use mediasoup::router::{Router, RouterOptions};
 use mediasoup::producer::{Producer, ProducerId};
 use ftl_protocol::protocol::FtlHandshakeFinalised;
@@ -16,6 +17,7 @@ pub struct HyperspeedRouter {
     pub router: Router,
     pub channel_id: String,
     pub producers: Vec<Producer>,
+    pub transport: PlainTransport,
     pub source: DataSource
 }
 
@@ -30,12 +32,13 @@ impl HyperspeedRouter {
             .await
             .unwrap();
 
-        let producers = init_producers(&router, &source, addr).await;
+        let (transport, producers) = init_producers(&router, &source, addr).await;
 
         HyperspeedRouter {
             router,
             channel_id,
             producers,
+            transport,
             source
         }
     }
@@ -44,6 +47,12 @@ impl HyperspeedRouter {
         self.router.clone()
     }
 
+    /// Address the plain transport is receiving RTP on.
+    pub fn get_transport_addr(&self) -> SocketAddr {
+        let tuple = self.transport.tuple();
+        SocketAddr::new(tuple.local_ip(), tuple.local_port())
+    }
+
     pub fn get_producer_ids(&self) -> Vec<ProducerId> {
         self.producers.iter()
             .map(|v| v.id().clone())
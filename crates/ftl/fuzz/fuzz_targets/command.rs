@@ -0,0 +1,18 @@
+#![no_main]
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+
+use ftl_protocol::protocol::{FtlCommand, FtlResponse};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(command) = FtlCommand::from_bytes(data) {
+        // Anything we accept should survive being sent again.
+        let again = FtlCommand::from_str(&command.to_string()).unwrap();
+        assert_eq!(again, command);
+    }
+
+    if let Ok(data) = std::str::from_utf8(data) {
+        let _ = FtlResponse::from_str(data);
+    }
+});
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ftl_protocol::session::{FtlSession, FtlSessionEvent};
+
+fuzz_target!(|data: &[u8]| {
+    let mut session = FtlSession::new();
+
+    // Feed the input in small chunks like a socket would.
+    for chunk in data.chunks(7) {
+        session.handle_input(chunk);
+
+        while let Some(event) = session.poll_event() {
+            match event {
+                FtlSessionEvent::AuthenticationRequested { .. } => session.authenticate("ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ"),
+                FtlSessionEvent::HandshakeFinalised { .. } => session.start_stream(24000),
+                _ => {}
+            }
+        }
+
+        session.take_output();
+    }
+});
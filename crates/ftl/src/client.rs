@@ -0,0 +1,427 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_channel::Receiver;
+use log::{debug, trace};
+
+use crate::protocol::{FtlCommand, FtlError, FtlFeature, FtlFeatures, FtlHandshakeFinalised, FtlResponse};
+use crate::rt::{self, AsyncReadExt, AsyncWriteExt, TcpStream, UdpSocket};
+use crate::signing::PacketKey;
+use crate::util;
+
+/// How often clients are expected to ping the server.
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// FTL control connection to an ingest server.
+pub struct FtlClient {
+    stream: TcpStream,
+    channel_id: String,
+    handshake: FtlHandshakeFinalised,
+    udp_port: u16,
+    /// Set if the server agreed to signed packets.
+    packet_key: Option<PacketKey>,
+    buffer: Vec<u8>,
+}
+
+impl FtlClient {
+    /// Connect to an ingest control server, authenticate with the
+    /// stream key and negotiate a media port using the given handshake.
+    pub async fn connect(addr: &str, channel_id: &str, stream_key: &str, handshake: FtlHandshakeFinalised) -> Result<FtlClient, FtlError> {
+        let stream = TcpStream::connect(addr).await?;
+
+        let mut client = FtlClient {
+            stream,
+            channel_id: channel_id.to_string(),
+            handshake,
+            udp_port: 0,
+            packet_key: None,
+            buffer: Vec::with_capacity(256),
+        };
+
+        let hmac_payload = client.authenticate(stream_key, false).await?;
+        debug!("Authenticated with ingest server as {}.", channel_id);
+
+        for attribute in client.handshake.attributes() {
+            client.send(attribute).await?;
+        }
+
+        client.send(FtlCommand::Dot).await?;
+        client.udp_port = match client.read_response().await? {
+            FtlResponse::Connect { udp_port, features } => {
+                // Servers which don't know about features don't use any.
+                client.handshake.features = features.unwrap_or_default();
+                udp_port
+            }
+            _ => return Err(FtlError::MissingPart),
+        };
+
+        if client.handshake.features.contains(&FtlFeature::SignedPackets) {
+            let packet_key = PacketKey::derive(&hmac_payload, stream_key).map_err(|_| FtlError::DecodeError)?;
+            client.packet_key = Some(packet_key);
+        }
+
+        debug!("Ingest server allocated UDP port {}.", client.udp_port);
+        Ok(client)
+    }
+
+    /// Reconnect after the control connection dropped and pick the stream back up.
+    ///
+    /// Only works if the server agreed to [`FtlFeature::Roaming`], and only until its resume
+    /// timeout runs out. The UDP port stays the same, open a new [`FtlClient::media_sender`]
+    /// if the network changed.
+    ///
+    /// [`FtlFeature::Roaming`]: crate::protocol::FtlFeature::Roaming
+    pub async fn resume(&mut self, addr: &str, stream_key: &str) -> Result<(), FtlError> {
+        self.stream = TcpStream::connect(addr).await?;
+        self.buffer.clear();
+
+        self.authenticate(stream_key, true).await?;
+        debug!("Resumed stream to {}.", &self.channel_id);
+        Ok(())
+    }
+
+    pub fn channel_id(&self) -> &str {
+        &self.channel_id
+    }
+
+    /// Features the server agreed to, those asked for in the handshake which it supports.
+    pub fn features(&self) -> &FtlFeatures {
+        &self.handshake.features
+    }
+
+    /// UDP port the server expects media on.
+    pub fn udp_port(&self) -> u16 {
+        self.udp_port
+    }
+
+    /// Open a UDP socket for sending media to the ingest server.
+    ///
+    /// Packets are signed if the server agreed to [`FtlFeature::SignedPackets`].
+    pub async fn media_sender(&self) -> io::Result<RtpSender> {
+        let peer = self.stream.peer_addr()?;
+        let local: SocketAddr = if peer.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(SocketAddr::new(peer.ip(), self.udp_port)).await?;
+
+        Ok(RtpSender {
+            socket,
+            video: self.handshake.video.as_ref().map(|video| RtpStream::new(video.payload_type, video.ssrc)),
+            audio: self.handshake.audio.as_ref().map(|audio| RtpStream::new(audio.payload_type, audio.ssrc)),
+            packet_key: self.packet_key.clone(),
+        })
+    }
+
+    pub async fn ping(&mut self) -> Result<(), FtlError> {
+        trace!("Sending ping for {}.", &self.channel_id);
+        self.send(FtlCommand::Ping { channel_id: self.channel_id.clone() }).await?;
+        self.read_response().await?;
+        Ok(())
+    }
+
+    /// Ping the server every [`PING_INTERVAL`] until the stop signal fires, then disconnect.
+    pub async fn keep_alive(mut self, stop_signal: Receiver<()>) -> Result<(), FtlError> {
+        while rt::timeout(PING_INTERVAL, stop_signal.recv()).await.is_none() {
+            self.ping().await?;
+        }
+
+        self.disconnect().await
+    }
+
+    pub async fn disconnect(mut self) -> Result<(), FtlError> {
+        debug!("Disconnecting from ingest server.");
+        self.send(FtlCommand::Disconnect).await?;
+        rt::shutdown(&mut self.stream).await;
+        Ok(())
+    }
+
+    /// Prove we hold the stream key, with `CONNECT` or with `RESUME` if `resume` is set.
+    ///
+    /// Returns the HMAC payload the server issued.
+    async fn authenticate(&mut self, stream_key: &str, resume: bool) -> Result<String, FtlError> {
+        self.send(FtlCommand::HMAC).await?;
+        let hmac_payload = match self.read_response().await? {
+            FtlResponse::HMAC { hmac_payload } => hmac_payload,
+            _ => return Err(FtlError::MissingPart),
+        };
+
+        let hashed_hmac_payload = util::hash_hmac(&hmac_payload, stream_key)
+            .map_err(|_| FtlError::DecodeError)?;
+
+        let channel_id = self.channel_id.clone();
+        let hashed_hmac_payload = format!("${}", hashed_hmac_payload);
+        self.send(if resume {
+            FtlCommand::Resume { channel_id, hashed_hmac_payload }
+        } else {
+            FtlCommand::Connect { channel_id, hashed_hmac_payload }
+        }).await?;
+
+        self.read_response().await?;
+        Ok(hmac_payload)
+    }
+
+    async fn send(&mut self, command: FtlCommand) -> Result<(), FtlError> {
+        // Match ftl-sdk, see the note on the protocol page.
+        self.stream.write_all(format!("{}\r\n\r\n", command).as_bytes())
+            .await
+            .map_err(FtlError::IoError)
+    }
+
+    /// Read the next response, turning error responses into errors.
+    async fn read_response(&mut self) -> Result<FtlResponse, FtlError> {
+        loop {
+            if let Some(position) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=position).collect();
+                let line = String::from_utf8(line).map_err(|_| FtlError::DecodeError)?;
+                let line = line.trim();
+
+                if line.is_empty() {
+                    continue;
+                }
+
+                return match FtlResponse::from_str(line)? {
+                    FtlResponse::Error { code, .. } => Err(FtlError::from_code(code).unwrap_or(FtlError::InternalServerError)),
+                    response => Ok(response),
+                };
+            }
+
+            let mut chunk = [0_u8; 512];
+            match self.stream.read(&mut chunk).await? {
+                0 => return Err(FtlError::InternalSocketClosed),
+                read => self.buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+}
+
+struct RtpStream {
+    payload_type: u8,
+    ssrc: u32,
+    sequence_number: u16,
+}
+
+impl RtpStream {
+    fn new(payload_type: u8, ssrc: u32) -> RtpStream {
+        RtpStream {
+            payload_type,
+            ssrc,
+            sequence_number: rand::random(),
+        }
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + payload.len());
+
+        // Version 2, no padding, no extension, no CSRCs.
+        packet.push(0x80);
+        packet.push(if marker { 0x80 } else { 0x00 } | (self.payload_type & 0x7F));
+        packet.extend_from_slice(&self.sequence_number.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        packet
+    }
+}
+
+/// Sends RTP packets for the negotiated streams to the ingest server.
+pub struct RtpSender {
+    socket: UdpSocket,
+    video: Option<RtpStream>,
+    audio: Option<RtpStream>,
+    packet_key: Option<PacketKey>,
+}
+
+impl RtpSender {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Wrap the payload in an RTP packet for the video stream and send it.
+    pub async fn send_video(&mut self, timestamp: u32, marker: bool, payload: &[u8]) -> io::Result<()> {
+        let stream = self.video.as_mut()
+            .ok_or_else(|| io::Error::other("video was not negotiated"))?;
+
+        let packet = stream.packet(timestamp, marker, payload);
+        self.send_packet(packet).await
+    }
+
+    /// Wrap the payload in an RTP packet for the audio stream and send it.
+    pub async fn send_audio(&mut self, timestamp: u32, marker: bool, payload: &[u8]) -> io::Result<()> {
+        let stream = self.audio.as_mut()
+            .ok_or_else(|| io::Error::other("audio was not negotiated"))?;
+
+        let packet = stream.packet(timestamp, marker, payload);
+        self.send_packet(packet).await
+    }
+
+    /// Send an already serialised RTP (or RTCP) packet, it is not signed.
+    ///
+    /// RTCP can't carry a signature, so ingests checking signatures may drop it.
+    pub async fn send_raw(&self, packet: &[u8]) -> io::Result<()> {
+        self.socket.send(packet).await?;
+        Ok(())
+    }
+
+    async fn send_packet(&self, packet: Vec<u8>) -> io::Result<()> {
+        let packet = match &self.packet_key {
+            Some(packet_key) => packet_key.sign(&packet).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+            None => packet,
+        };
+
+        self.socket.send(&packet).await?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "session", any(feature = "server", feature = "tokio")))]
+mod tests {
+    use async_channel::{bounded, Receiver};
+
+    use crate::client::FtlClient;
+    use crate::protocol::{FtlFeature, FtlHandshakeFinalised, KnownAudio, ProtocolVersion, Vendor};
+    use crate::rt::{self, AsyncReadExt, AsyncWriteExt, JoinHandle, TcpListener, UdpSocket};
+    use crate::session::{FtlSession, FtlSessionEvent};
+    use crate::signing::{PacketKey, ReplayWindow, SignatureError};
+
+    const STREAM_KEY: &str = "ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ";
+
+    /// Run a session for one client, handing out the packet key once the stream starts.
+    fn serve(listener: TcpListener, mut session: FtlSession, udp_port: u16) -> (JoinHandle<Vec<FtlSessionEvent>>, Receiver<Option<PacketKey>>) {
+        let (packet_key, packet_key_receiver) = bounded(1);
+
+        let server = rt::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut events = vec![];
+            let mut chunk = [0_u8; 1024];
+
+            while !session.is_closed() {
+                let read = stream.read(&mut chunk).await.unwrap();
+                if read == 0 {
+                    session.close();
+                }
+
+                session.handle_input(&chunk[..read]);
+                while let Some(event) = session.poll_event() {
+                    match &event {
+                        FtlSessionEvent::AuthenticationRequested { .. } => session.authenticate(STREAM_KEY),
+                        FtlSessionEvent::HandshakeFinalised { .. } => {
+                            session.start_stream(udp_port);
+                            packet_key.try_send(session.packet_key().cloned()).ok();
+                        }
+                        _ => {}
+                    }
+
+                    events.push(event);
+                }
+
+                stream.write_all(&session.take_output()).await.unwrap();
+            }
+
+            events
+        });
+
+        (server, packet_key_receiver)
+    }
+
+    fn handshake() -> FtlHandshakeFinalised {
+        FtlHandshakeFinalised {
+            protocol_version: ProtocolVersion::new(0, 9),
+            vendor: Vendor {
+                name: Some("hyperspeed".to_string()),
+                version: None,
+            },
+            video: None,
+            audio: Some(KnownAudio {
+                codec: "OPUS".to_string(),
+                payload_type: 97,
+                ssrc: 77,
+            }),
+            features: Default::default(),
+            unknown_attributes: Default::default(),
+        }
+    }
+
+    #[rt::test]
+    async fn should_stream_to_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let media = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_port = media.local_addr().unwrap().port();
+        let (server, _) = serve(listener, FtlSession::new(), udp_port);
+
+        let mut client = FtlClient::connect(&addr.to_string(), "77", STREAM_KEY, handshake()).await.unwrap();
+        assert_eq!(client.udp_port(), udp_port);
+
+        let mut sender = client.media_sender().await.unwrap();
+        sender.send_audio(1234, true, b"opus").await.unwrap();
+
+        let mut packet = [0_u8; 64];
+        let length = media.recv(&mut packet).await.unwrap();
+        assert_eq!(length, 16);
+        assert_eq!(packet[1], 0x80 | 97);
+        assert_eq!(&packet[8..12], &77_u32.to_be_bytes());
+        assert_eq!(&packet[12..16], b"opus");
+
+        client.ping().await.unwrap();
+        client.disconnect().await.unwrap();
+
+        let events = rt::join(server).await;
+        assert!(matches!(events[1], FtlSessionEvent::Authenticated { .. }));
+        assert!(matches!(events[2], FtlSessionEvent::HandshakeFinalised { .. }));
+        assert!(matches!(events[3], FtlSessionEvent::Ping { .. }));
+        assert!(matches!(events[4], FtlSessionEvent::Disconnected { error: None }));
+    }
+
+    #[rt::test]
+    async fn should_sign_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let media = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_port = media.local_addr().unwrap().port();
+
+        let supported = vec![FtlFeature::SignedPackets].into_iter().collect();
+        let (server, packet_key) = serve(listener, FtlSession::new().with_supported_features(supported), udp_port);
+
+        let mut handshake = handshake();
+        handshake.features.insert(FtlFeature::SignedPackets);
+        let client = FtlClient::connect(&addr.to_string(), "77", STREAM_KEY, handshake).await.unwrap();
+        assert!(client.features().contains(&FtlFeature::SignedPackets));
+
+        let packet_key = packet_key.recv().await.unwrap().unwrap();
+        let mut sender = client.media_sender().await.unwrap();
+        sender.send_audio(1234, true, b"opus").await.unwrap();
+
+        let mut packet = [0_u8; 64];
+        let length = media.recv(&mut packet).await.unwrap();
+        assert_eq!(length, 36);
+        assert_eq!(&packet[32..36], b"opus");
+        assert_eq!(packet_key.verify(&packet[..length]), Ok(()));
+
+        // Someone else on the path can't produce a valid signature.
+        let mut forged = packet[..length].to_vec();
+        forged[8..12].copy_from_slice(&78_u32.to_be_bytes());
+        assert_eq!(packet_key.verify(&forged), Err(SignatureError::Forged));
+
+        // Sending a captured packet again still verifies, the replay window catches it.
+        sender.send_raw(&packet[..length]).await.unwrap();
+        let mut replayed = [0_u8; 64];
+        let replayed_length = media.recv(&mut replayed).await.unwrap();
+        assert_eq!(packet_key.verify(&replayed[..replayed_length]), Ok(()));
+
+        let sequence_number = u16::from_be_bytes([packet[2], packet[3]]);
+        let mut window = ReplayWindow::default();
+        assert_eq!(window.check(sequence_number), Ok(()));
+        assert_eq!(window.check(u16::from_be_bytes([replayed[2], replayed[3]])), Err(SignatureError::Replayed));
+
+        client.disconnect().await.unwrap();
+        rt::join(server).await;
+    }
+}
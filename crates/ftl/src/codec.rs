@@ -0,0 +1,244 @@
+//! Framing for the FTL control connection, for use with `Framed` from
+//! asynchronous-codec (`codec` feature) or tokio-util (`tokio-codec` feature).
+//!
+//! Lines end in `\n`, carriage returns are ignored and blank lines are skipped,
+//! which covers both the `\r\n` and the `\r\n\r\n` endings clients use.
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::protocol::{self, FtlCommand, FtlError, FtlResponse};
+
+/// Longest line accepted by default, in bytes, matching [`FtlLimits`].
+///
+/// [`FtlLimits`]: crate::session::FtlLimits
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 1024;
+
+/// Server side codec, reads [`FtlCommand`]s and writes [`FtlResponse`]s.
+///
+/// Lines which don't parse as a command are consumed before the error is returned,
+/// so reading can carry on past them.
+#[derive(Debug, Clone)]
+pub struct FtlCodec {
+    max_line_length: usize,
+}
+
+/// Client side codec, reads [`FtlResponse`]s and writes [`FtlCommand`]s.
+#[derive(Debug, Clone)]
+pub struct FtlClientCodec {
+    max_line_length: usize,
+}
+
+impl Default for FtlCodec {
+    fn default() -> Self {
+        FtlCodec::new()
+    }
+}
+
+impl FtlCodec {
+    pub fn new() -> FtlCodec {
+        FtlCodec {
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+
+    /// Lines longer than this fail with [`FtlError::LineTooLong`].
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> FtlCodec {
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    pub fn decode_command(&mut self, src: &mut BytesMut) -> Result<Option<FtlCommand>, FtlError> {
+        match next_line(src, self.max_line_length)? {
+            Some(line) => FtlCommand::from_bytes(&line).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn encode_response(&mut self, response: FtlResponse, dst: &mut BytesMut) -> Result<(), FtlError> {
+        // Responses carry their own line ending.
+        dst.put_slice(response.to_string().as_bytes());
+        Ok(())
+    }
+}
+
+impl Default for FtlClientCodec {
+    fn default() -> Self {
+        FtlClientCodec::new()
+    }
+}
+
+impl FtlClientCodec {
+    pub fn new() -> FtlClientCodec {
+        FtlClientCodec {
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+
+    /// Lines longer than this fail with [`FtlError::LineTooLong`].
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> FtlClientCodec {
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    pub fn decode_response(&mut self, src: &mut BytesMut) -> Result<Option<FtlResponse>, FtlError> {
+        match next_line(src, self.max_line_length)? {
+            Some(line) => {
+                let line = std::str::from_utf8(&line).map_err(|_| FtlError::UnimplementedCommand)?;
+                line.parse().map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn encode_command(&mut self, command: FtlCommand, dst: &mut BytesMut) -> Result<(), FtlError> {
+        // Match ftl-sdk, see the note on the protocol page.
+        dst.put_slice(format!("{}\r\n\r\n", command).as_bytes());
+        Ok(())
+    }
+}
+
+/// Split the next non-blank line off `src`, without its line ending.
+fn next_line(src: &mut BytesMut, max_line_length: usize) -> Result<Option<Vec<u8>>, FtlError> {
+    let (consumed, line) = protocol::next_line(src, max_line_length)?;
+    src.advance(consumed);
+    Ok(line)
+}
+
+#[cfg(feature = "codec")]
+impl asynchronous_codec::Decoder for FtlCodec {
+    type Item = FtlCommand;
+    type Error = FtlError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_command(src)
+    }
+}
+
+#[cfg(feature = "codec")]
+impl asynchronous_codec::Encoder for FtlCodec {
+    type Item = FtlResponse;
+    type Error = FtlError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_response(item, dst)
+    }
+}
+
+#[cfg(feature = "codec")]
+impl asynchronous_codec::Decoder for FtlClientCodec {
+    type Item = FtlResponse;
+    type Error = FtlError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_response(src)
+    }
+}
+
+#[cfg(feature = "codec")]
+impl asynchronous_codec::Encoder for FtlClientCodec {
+    type Item = FtlCommand;
+    type Error = FtlError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_command(item, dst)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl tokio_util::codec::Decoder for FtlCodec {
+    type Item = FtlCommand;
+    type Error = FtlError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_command(src)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl tokio_util::codec::Encoder<FtlResponse> for FtlCodec {
+    type Error = FtlError;
+
+    fn encode(&mut self, item: FtlResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_response(item, dst)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl tokio_util::codec::Decoder for FtlClientCodec {
+    type Item = FtlResponse;
+    type Error = FtlError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_response(src)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl tokio_util::codec::Encoder<FtlCommand> for FtlClientCodec {
+    type Error = FtlError;
+
+    fn encode(&mut self, item: FtlCommand, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_command(item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::codec::{FtlClientCodec, FtlCodec};
+    use crate::protocol::{FtlCommand, FtlError, FtlResponse};
+
+    #[test]
+    fn should_decode_commands() {
+        let mut codec = FtlCodec::new();
+        let mut src = BytesMut::from(&b"HMAC\r\n\r\nCONNECT 77 $abc\r\n\r\nPING 7"[..]);
+
+        assert_eq!(codec.decode_command(&mut src).unwrap(), Some(FtlCommand::HMAC));
+        assert_eq!(codec.decode_command(&mut src).unwrap(), Some(FtlCommand::Connect {
+            channel_id: "77".to_string(),
+            hashed_hmac_payload: "$abc".to_string(),
+        }));
+
+        // Wait for the rest of the line.
+        assert_eq!(codec.decode_command(&mut src).unwrap(), None);
+        src.extend_from_slice(b"7\n.\n");
+        assert_eq!(codec.decode_command(&mut src).unwrap(), Some(FtlCommand::Ping { channel_id: "77".to_string() }));
+        assert_eq!(codec.decode_command(&mut src).unwrap(), Some(FtlCommand::Dot));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn should_limit_line_length() {
+        let mut codec = FtlCodec::new().with_max_line_length(8);
+
+        let mut src = BytesMut::from(&b"VendorName: OBS\r\n"[..]);
+        assert!(matches!(codec.decode_command(&mut src), Err(FtlError::LineTooLong)));
+
+        let mut src = BytesMut::from(&b"HMACHMACHMAC"[..]);
+        assert!(matches!(codec.decode_command(&mut src), Err(FtlError::LineTooLong)));
+
+        // Unknown commands are skipped over.
+        let mut src = BytesMut::from(&b"NOPE\r\nHMAC\r\n"[..]);
+        assert!(codec.decode_command(&mut src).is_err());
+        assert_eq!(codec.decode_command(&mut src).unwrap(), Some(FtlCommand::HMAC));
+    }
+
+    #[test]
+    fn should_round_trip_through_both_codecs() {
+        let mut server = FtlCodec::new();
+        let mut client = FtlClientCodec::new();
+        let mut buffer = BytesMut::new();
+
+        client.encode_command(FtlCommand::Ping { channel_id: "77".to_string() }, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], b"PING 77\r\n\r\n");
+        assert_eq!(server.decode_command(&mut buffer).unwrap(), Some(FtlCommand::Ping { channel_id: "77".to_string() }));
+        assert_eq!(server.decode_command(&mut buffer).unwrap(), None);
+
+        server.encode_response(FtlResponse::Connect { udp_port: 24000, features: None }, &mut buffer).unwrap();
+        server.encode_response(FtlResponse::Pong, &mut buffer).unwrap();
+        assert_eq!(client.decode_response(&mut buffer).unwrap(), Some(FtlResponse::Connect { udp_port: 24000, features: None }));
+        assert_eq!(client.decode_response(&mut buffer).unwrap(), Some(FtlResponse::Pong));
+        assert!(buffer.is_empty());
+    }
+}
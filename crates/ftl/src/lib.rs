@@ -2,10 +2,37 @@
 
 pub mod protocol;
 
-#[cfg(feature = "server")]
-#[cfg_attr(docsrs, doc(cfg(feature = "server")))]
+#[cfg(any(feature = "server", feature = "tokio"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "server", feature = "tokio"))))]
 pub mod server;
 
+#[cfg(any(feature = "server", feature = "tokio"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "server", feature = "tokio"))))]
+pub mod ports;
+
+#[cfg(feature = "client")]
+#[cfg_attr(docsrs, doc(cfg(feature = "client")))]
+pub mod client;
+
+#[cfg(any(feature = "server", feature = "tokio", feature = "client"))]
+mod rt;
+
+#[cfg(any(feature = "codec", feature = "tokio-codec"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "codec", feature = "tokio-codec"))))]
+pub mod codec;
+
+#[cfg(feature = "session")]
+#[cfg_attr(docsrs, doc(cfg(feature = "session")))]
+pub mod session;
+
+#[cfg(any(feature = "server", feature = "tokio"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "server", feature = "tokio"))))]
+pub mod proxy;
+
 #[cfg(feature = "util")]
 #[cfg_attr(docsrs, doc(cfg(feature = "util")))]
 pub mod util;
+
+#[cfg(feature = "util")]
+#[cfg_attr(docsrs, doc(cfg(feature = "util")))]
+pub mod signing;
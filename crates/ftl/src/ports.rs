@@ -0,0 +1,170 @@
+//! UDP port allocation for media ingest.
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_channel::Receiver;
+use log::{debug, error};
+use rand::Rng;
+
+use crate::rt;
+
+/// Number of random ports tried before giving up.
+const ATTEMPTS: usize = 64;
+
+/// Hands out sockets bound to random UDP ports from a range, for use in [`IngestServer::allocate_ingest`].
+///
+/// Picking ports at random makes it harder for a third party to guess where
+/// media is sent. Clones share the same set of ports.
+///
+/// [`IngestServer::allocate_ingest`]: crate::server::IngestServer::allocate_ingest
+#[derive(Debug, Clone)]
+pub struct PortAllocator {
+    range: RangeInclusive<u16>,
+    ip: IpAddr,
+    in_use: Arc<Mutex<HashSet<u16>>>,
+}
+
+impl Default for PortAllocator {
+    /// Ports 24000 to 65000, as recommended by the security notes.
+    fn default() -> Self {
+        PortAllocator::new(24000..=65000)
+    }
+}
+
+impl PortAllocator {
+    pub fn new(range: RangeInclusive<u16>) -> PortAllocator {
+        PortAllocator {
+            range,
+            ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            in_use: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Address sockets are bound to, the unspecified IPv4 address by default.
+    pub fn with_ip(mut self, ip: IpAddr) -> PortAllocator {
+        self.ip = ip;
+        self
+    }
+
+    /// Bind a socket on a random port, releasing the port once `stop_signal` closes.
+    ///
+    /// Pass the stop signal given to `allocate_ingest`. The socket is non-blocking, ready to be
+    /// handed to your runtime. Returns `None` if no free port was found.
+    pub fn allocate(&self, stop_signal: &Receiver<()>) -> Option<UdpSocket> {
+        let socket = self.reserve()?;
+        let port = socket.local_addr().ok()?.port();
+
+        let allocator = self.clone();
+        let stop_signal = stop_signal.clone();
+        rt::spawn(async move {
+            while stop_signal.recv().await.is_ok() {}
+            allocator.release(port);
+        });
+
+        Some(socket)
+    }
+
+    /// Bind a socket on a random port, the port must be given back with [`PortAllocator::release`].
+    ///
+    /// Gives up after a fixed number of attempts, so a nearly full range may return `None`
+    /// even though some ports are still free.
+    pub fn reserve(&self) -> Option<UdpSocket> {
+        let (start, end) = (*self.range.start(), *self.range.end());
+        if start > end {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..ATTEMPTS {
+            let port = rng.gen_range(start..=end);
+            if !self.in_use().insert(port) {
+                continue;
+            }
+
+            // Bind outside the lock, the port is already marked as ours.
+            match self.bind(port) {
+                Ok(socket) => {
+                    debug!("Reserved UDP port {}.", port);
+                    return Some(socket);
+                }
+                Err(_) => {
+                    self.in_use().remove(&port);
+                }
+            }
+        }
+
+        error!("No free UDP port found between {} and {}.", start, end);
+        None
+    }
+
+    /// Give a port back so it can be handed out again.
+    pub fn release(&self, port: u16) {
+        if self.in_use().remove(&port) {
+            debug!("Released UDP port {}.", port);
+        }
+    }
+
+    /// Number of ports currently reserved.
+    pub fn in_use_count(&self) -> usize {
+        self.in_use().len()
+    }
+
+    fn bind(&self, port: u16) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((self.ip, port))?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    fn in_use(&self) -> MutexGuard<'_, HashSet<u16>> {
+        self.in_use.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use async_channel::bounded;
+
+    use crate::ports::PortAllocator;
+    use crate::rt;
+
+    #[rt::test]
+    async fn should_allocate_and_release() {
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let allocator = PortAllocator::new(port..=port);
+        let (stop, stop_signal) = bounded::<()>(1);
+
+        // Ports which can't be bound are skipped.
+        assert!(allocator.allocate(&stop_signal).is_none());
+        assert_eq!(allocator.in_use_count(), 0);
+        drop(socket);
+
+        let socket = allocator.allocate(&stop_signal).unwrap();
+        assert_eq!(socket.local_addr().unwrap().port(), port);
+        assert!(allocator.allocate(&stop_signal).is_none());
+        assert_eq!(allocator.in_use_count(), 1);
+
+        drop(socket);
+        stop.close();
+        rt::sleep(Duration::from_millis(50)).await;
+        assert_eq!(allocator.in_use_count(), 0);
+        assert_eq!(allocator.reserve().unwrap().local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn should_randomize_ports() {
+        let allocator = PortAllocator::default();
+        let sockets: Vec<UdpSocket> = (0..8).filter_map(|_| allocator.reserve()).collect();
+        let ports: Vec<u16> = sockets.iter().map(|socket| socket.local_addr().unwrap().port()).collect();
+
+        assert!(ports.iter().all(|port| (24000..=65000).contains(port)));
+        assert!(ports.windows(2).any(|pair| pair[1] != pair[0] + 1), "{:?}", ports);
+    }
+}
@@ -1,14 +1,20 @@
+use std::fmt;
 use std::str::FromStr;
 
 use super::FtlError;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FtlCommand {
     HMAC,
     Connect {
         channel_id: String,
         hashed_hmac_payload: String,
     },
+    /// Reattach to a stream whose control connection dropped, see the roaming proposal.
+    Resume {
+        channel_id: String,
+        hashed_hmac_payload: String,
+    },
     Dot,
     Attribute {
         key: String,
@@ -20,59 +26,97 @@ pub enum FtlCommand {
     Disconnect,
 }
 
+/// Serialise the command without a line terminator,
+/// clients should follow it with `\r\n\r\n` like ftl-sdk does.
+impl fmt::Display for FtlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FtlCommand::HMAC => f.write_str("HMAC"),
+            FtlCommand::Connect { channel_id, hashed_hmac_payload } =>
+                write!(f, "CONNECT {} {}", channel_id, hashed_hmac_payload),
+            FtlCommand::Resume { channel_id, hashed_hmac_payload } =>
+                write!(f, "RESUME {} {}", channel_id, hashed_hmac_payload),
+            FtlCommand::Dot => f.write_str("."),
+            FtlCommand::Attribute { key, value } => write!(f, "{}: {}", key, value),
+            FtlCommand::Ping { channel_id } => write!(f, "PING {}", channel_id),
+            FtlCommand::Disconnect => f.write_str("DISCONNECT"),
+        }
+    }
+}
+
+impl FtlCommand {
+    /// Parse a command from raw bytes read off the control socket.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FtlError> {
+        std::str::from_utf8(bytes)
+            .map_err(|_| FtlError::UnimplementedCommand)?
+            .parse()
+    }
+}
+
+/// Parse a single command, trailing line terminators
+/// such as ftl-sdk's `\r\n\r\n` are ignored.
 impl FromStr for FtlCommand {
     type Err = FtlError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "HMAC" => Ok(FtlCommand::HMAC),
-            "." => Ok(FtlCommand::Dot),
-            "DISCONNECT" => Ok(FtlCommand::Disconnect),
-            s => {
-                if &s[..4] == "PING" {
-                    Ok(FtlCommand::Ping {
-                        channel_id: s[5..].to_string(),
-                    })
-                } else if &s[..7] == "CONNECT" {
-                    let parts = &mut s[8..].split(" ");
-
-                    Ok(FtlCommand::Connect {
-                        channel_id: parts
-                            .next()
-                            .ok_or_else(|| FtlError::MissingPart)?
-                            .to_string(),
-                        hashed_hmac_payload: parts
-                            .next()
-                            .ok_or_else(|| FtlError::MissingPart)?
-                            .to_string(),
-                    })
+        let s = s.trim_end_matches(['\r', '\n']);
+        let (verb, args) = match s.split_once(' ') {
+            Some((verb, args)) => (verb, Some(args)),
+            None => (s, None),
+        };
+
+        match (verb, args) {
+            ("HMAC", None) => Ok(FtlCommand::HMAC),
+            (".", None) => Ok(FtlCommand::Dot),
+            ("DISCONNECT", None) => Ok(FtlCommand::Disconnect),
+            ("PING", args) => Ok(FtlCommand::Ping {
+                channel_id: word(args)?.to_string(),
+            }),
+            ("CONNECT", args) | ("RESUME", args) => {
+                let (channel_id, hashed_hmac_payload) = args
+                    .and_then(|args| args.split_once(' '))
+                    .ok_or(FtlError::MissingPart)?;
+
+                let channel_id = word(Some(channel_id))?.to_string();
+                let hashed_hmac_payload = word(Some(hashed_hmac_payload))?.to_string();
+
+                Ok(if verb == "CONNECT" {
+                    FtlCommand::Connect { channel_id, hashed_hmac_payload }
                 } else {
-                    if s.contains(':') {
-                        let mut parts = s.split(':').map(|v| v.trim());
-
-                        return Ok(FtlCommand::Attribute {
-                            key: parts
-                                .next()
-                                .ok_or_else(|| FtlError::MissingPart)?
-                                .to_string(),
-                            value: parts
-                                .next()
-                                .ok_or_else(|| FtlError::MissingPart)?
-                                .to_string(),
-                        });
-                    }
-
-                    Err(FtlError::UnimplementedCommand)
+                    FtlCommand::Resume { channel_id, hashed_hmac_payload }
+                })
+            }
+            _ => {
+                let (key, value) = s.split_once(':').ok_or(FtlError::UnimplementedCommand)?;
+                let key = key.trim();
+
+                if key.is_empty() || key.contains(char::is_whitespace) {
+                    return Err(FtlError::UnimplementedCommand);
                 }
+
+                Ok(FtlCommand::Attribute {
+                    key: key.to_string(),
+                    value: value.trim().to_string(),
+                })
             }
         }
     }
 }
 
+/// A single non-empty argument without any whitespace.
+fn word(arg: Option<&str>) -> Result<&str, FtlError> {
+    match arg {
+        Some(arg) if !arg.is_empty() && !arg.contains(char::is_whitespace) => Ok(arg),
+        _ => Err(FtlError::MissingPart),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
+    use proptest::prelude::*;
+
     use crate::protocol::FtlCommand;
 
     #[test]
@@ -105,6 +149,13 @@ mod tests {
         assert_eq!(command, FtlCommand::Connect { channel_id: "channel_id".to_string(), hashed_hmac_payload: "hmac".to_string() });
     }
 
+    #[test]
+    fn should_parse_resume() {
+        let command = FtlCommand::from_str("RESUME channel_id hmac").unwrap();
+        assert_eq!(command, FtlCommand::Resume { channel_id: "channel_id".to_string(), hashed_hmac_payload: "hmac".to_string() });
+        assert!(FtlCommand::from_str("RESUME channel_id").is_err());
+    }
+
     #[test]
     fn should_parse_attribute() {
         let command = FtlCommand::from_str("ProtocolVersion: 0.9").unwrap();
@@ -117,6 +168,70 @@ mod tests {
         assert!(command.is_err())
     }
 
+    #[test]
+    fn should_reject_short_and_partial_commands() {
+        for input in ["", "ABC", "PING", "PING ", "CONNECT", "CONNECT 77", "CONNECT 77 ", "CONNECT 77 $ab extra", "HMAC now", ": value", "é"] {
+            assert!(FtlCommand::from_str(input).is_err(), "{:?} should be rejected", input);
+        }
+    }
+
+    #[test]
+    fn should_ignore_sdk_framing() {
+        let command = FtlCommand::from_str("PING 77\r\n\r\n").unwrap();
+        assert_eq!(command, FtlCommand::Ping { channel_id: "77".to_string() });
+
+        let command = FtlCommand::from_bytes(b"VendorVersion: 0.0.1:beta\r\n").unwrap();
+        assert_eq!(command, FtlCommand::Attribute { key: "VendorVersion".to_string(), value: "0.0.1:beta".to_string() });
+
+        assert!(FtlCommand::from_bytes(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn should_round_trip() {
+        for command in [
+            FtlCommand::HMAC,
+            FtlCommand::Connect { channel_id: "77".to_string(), hashed_hmac_payload: "$abcdef".to_string() },
+            FtlCommand::Dot,
+            FtlCommand::Attribute { key: "VendorName".to_string(), value: "OBS Studio".to_string() },
+            FtlCommand::Ping { channel_id: "77".to_string() },
+            FtlCommand::Disconnect,
+        ] {
+            assert_eq!(FtlCommand::from_str(&command.to_string()).unwrap(), command);
+        }
+    }
+
+    fn command() -> impl Strategy<Value = FtlCommand> {
+        let word = "[!-~]{1,32}";
+
+        prop_oneof![
+            Just(FtlCommand::HMAC),
+            (word, word).prop_map(|(channel_id, hashed_hmac_payload)| FtlCommand::Connect { channel_id, hashed_hmac_payload }),
+            (word, word).prop_map(|(channel_id, hashed_hmac_payload)| FtlCommand::Resume { channel_id, hashed_hmac_payload }),
+            Just(FtlCommand::Dot),
+            ("[A-Za-z]{1,32}", "([!-~]([ -~]{0,30}[!-~])?)?").prop_map(|(key, value)| FtlCommand::Attribute { key, value }),
+            word.prop_map(|channel_id| FtlCommand::Ping { channel_id }),
+            Just(FtlCommand::Disconnect),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn should_never_panic_on_bytes(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = FtlCommand::from_bytes(&bytes);
+        }
+
+        #[test]
+        fn should_never_panic_on_strings(s in "\\PC*") {
+            let _ = FtlCommand::from_str(&s);
+        }
+
+        #[test]
+        fn should_round_trip_any_command(command in command()) {
+            let framed = format!("{}\r\n\r\n", command);
+            prop_assert_eq!(FtlCommand::from_str(&framed).unwrap(), command);
+        }
+    }
+
     #[test]
     fn doc_test() {
         use crate::protocol::FtlCommand;
@@ -1,56 +1,230 @@
-use std::string::ToString;
+use std::{error, fmt, io};
+
+use super::FtlResponse;
 
 #[derive(Debug)]
 pub enum FtlError {
-    IoError,
+    IoError(io::Error),
     AllocateError,
     RingError,
     DecodeError,
+    /// 400 Bad Request
     MissingPart,
+    /// 500 Internal Server Error
+    InternalServerError,
 
     InvalidStreamKey, // applies to channel id as well
     ChannelNotAuthorized,
     ChannelInUse,
     UnsupportedRegion,
     GameBlocked,
-    
+    AudioSsrcCollision,
+    VideoSsrcCollision,
+    NoMediaTimeout,
+    ServerTerminate,
+
     InvalidProtocolVersion,
     UnsupportedProtocolVersion,
-    MissingCodecInformation,
+    MissingCodecInformation { attribute: &'static str },
+    InvalidAttribute { key: String, value: String },
+    LineTooLong,
+    TooManyAttributes,
+    /// 901 Internal Command Error
     UnimplementedCommand,
-    
+    InternalMemoryError,
+    InternalSocketClosed,
+    InternalSocketTimeout,
+
     Disconnect,
 }
 
 impl FtlError {
     pub fn is_err(&self) -> bool {
+        !matches!(self, FtlError::Disconnect)
+    }
+
+    /// Status code sent to the client for this error.
+    pub fn code(&self) -> Option<u16> {
+        Some(match self {
+            FtlError::IoError(_) => 500,
+            FtlError::AllocateError => 500,
+            FtlError::RingError => 400,
+            FtlError::DecodeError => 400,
+            FtlError::MissingPart => 400,
+            FtlError::InternalServerError => 500,
+
+            FtlError::InvalidStreamKey => 405,
+            FtlError::ChannelNotAuthorized => 401,
+            FtlError::ChannelInUse => 406,
+            FtlError::UnsupportedRegion => 407,
+            FtlError::GameBlocked => 409,
+            FtlError::AudioSsrcCollision => 403,
+            FtlError::VideoSsrcCollision => 404,
+            FtlError::NoMediaTimeout => 408,
+            FtlError::ServerTerminate => 410,
+
+            FtlError::InvalidProtocolVersion => 400,
+            FtlError::UnsupportedProtocolVersion => 402,
+            FtlError::MissingCodecInformation { .. } => 400,
+            FtlError::InvalidAttribute { .. } => 400,
+            FtlError::LineTooLong => 400,
+            FtlError::TooManyAttributes => 400,
+            FtlError::UnimplementedCommand => 901,
+            FtlError::InternalMemoryError => 900,
+            FtlError::InternalSocketClosed => 902,
+            FtlError::InternalSocketTimeout => 903,
+
+            FtlError::Disconnect => return None,
+        })
+    }
+
+    /// Map a status code received from a server back to an error.
+    ///
+    /// Codes shared by several errors (400, 500) map to the most general one.
+    pub fn from_code(code: u16) -> Option<FtlError> {
+        Some(match code {
+            400 => FtlError::MissingPart,
+            401 => FtlError::ChannelNotAuthorized,
+            402 => FtlError::UnsupportedProtocolVersion,
+            403 => FtlError::AudioSsrcCollision,
+            404 => FtlError::VideoSsrcCollision,
+            405 => FtlError::InvalidStreamKey,
+            406 => FtlError::ChannelInUse,
+            407 => FtlError::UnsupportedRegion,
+            408 => FtlError::NoMediaTimeout,
+            409 => FtlError::GameBlocked,
+            410 => FtlError::ServerTerminate,
+            500 => FtlError::InternalServerError,
+            900 => FtlError::InternalMemoryError,
+            901 => FtlError::UnimplementedCommand,
+            902 => FtlError::InternalSocketClosed,
+            903 => FtlError::InternalSocketTimeout,
+            _ => return None,
+        })
+    }
+
+    /// Response line sent to the client, `None` if nothing should be sent.
+    pub fn to_response(&self) -> Option<FtlResponse> {
+        let message = match self {
+            FtlError::IoError(_) => "Internal Server Error",
+            FtlError::AllocateError => "Internal Server Error",
+            FtlError::RingError => "HMAC Decode Error",
+            FtlError::DecodeError => "HMAC Decode Error",
+            FtlError::MissingPart => "Bad Request",
+            FtlError::InternalServerError => "Internal Server Error",
+
+            FtlError::InvalidStreamKey => "Invalid stream key",
+            FtlError::ChannelNotAuthorized => "Channel not authorized to stream",
+            FtlError::ChannelInUse => "Channel actively streaming",
+            FtlError::UnsupportedRegion => "Streaming from your region is not authorized",
+            FtlError::GameBlocked => "Channel is not allowed to stream set game",
+            FtlError::AudioSsrcCollision => "Audio SSRC collision",
+            FtlError::VideoSsrcCollision => "Video SSRC collision",
+            FtlError::NoMediaTimeout => "No media received",
+            FtlError::ServerTerminate => "Server terminated stream",
+
+            FtlError::InvalidProtocolVersion => "Invalid Protocol Version",
+            FtlError::UnsupportedProtocolVersion => "Outdated FTL SDK version",
+            FtlError::MissingCodecInformation { .. } => "Missing Codec Information",
+            FtlError::InvalidAttribute { .. } => "Bad Request",
+            FtlError::LineTooLong => "Bad Request",
+            FtlError::TooManyAttributes => "Bad Request",
+            FtlError::UnimplementedCommand => "Invalid Command",
+            FtlError::InternalMemoryError => "Internal Memory Error",
+            FtlError::InternalSocketClosed => "Internal Socket Closed",
+            FtlError::InternalSocketTimeout => "Internal Socket Timeout",
+
+            FtlError::Disconnect => return None,
+        };
+
+        Some(FtlResponse::Error {
+            code: self.code()?,
+            message: message.to_string(),
+        })
+    }
+}
+
+/// Describes the error for logs.
+///
+/// Before 0.1 `to_string` gave the line sent to the client, use [`FtlError::to_response`] for that.
+impl fmt::Display for FtlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            FtlError::Disconnect => false,
-            _ => true
+            FtlError::IoError(error) => write!(f, "I/O error: {}", error),
+            FtlError::AllocateError => f.write_str("failed to allocate media ingest"),
+            FtlError::RingError => f.write_str("HMAC verification failed"),
+            FtlError::DecodeError => f.write_str("failed to decode HMAC payload"),
+            FtlError::MissingPart => f.write_str("command is missing a part"),
+            FtlError::InternalServerError => f.write_str("internal server error"),
+
+            FtlError::InvalidStreamKey => f.write_str("invalid stream key"),
+            FtlError::ChannelNotAuthorized => f.write_str("channel is not authorized to stream"),
+            FtlError::ChannelInUse => f.write_str("channel is already streaming"),
+            FtlError::UnsupportedRegion => f.write_str("streaming from this region is not authorized"),
+            FtlError::GameBlocked => f.write_str("channel is not allowed to stream the set game"),
+            FtlError::AudioSsrcCollision => f.write_str("audio SSRC collides with another stream"),
+            FtlError::VideoSsrcCollision => f.write_str("video SSRC collides with another stream"),
+            FtlError::NoMediaTimeout => f.write_str("no media received before timeout"),
+            FtlError::ServerTerminate => f.write_str("server terminated the stream"),
+
+            FtlError::InvalidProtocolVersion => f.write_str("invalid or missing protocol version"),
+            FtlError::UnsupportedProtocolVersion => f.write_str("unsupported protocol version"),
+            FtlError::MissingCodecInformation { attribute } => write!(f, "missing codec information, {} was not given", attribute),
+            FtlError::InvalidAttribute { key, value } => write!(f, "invalid value `{}` for attribute {}", value, key),
+            FtlError::LineTooLong => f.write_str("line exceeds the maximum length"),
+            FtlError::TooManyAttributes => f.write_str("handshake has too many attributes"),
+            FtlError::UnimplementedCommand => f.write_str("invalid command"),
+            FtlError::InternalMemoryError => f.write_str("internal memory error"),
+            FtlError::InternalSocketClosed => f.write_str("socket closed unexpectedly"),
+            FtlError::InternalSocketTimeout => f.write_str("no data received before timeout"),
+
+            FtlError::Disconnect => f.write_str("client disconnected"),
         }
     }
 }
 
-impl ToString for FtlError {
-    fn to_string(&self) -> String {
+impl error::Error for FtlError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
-            FtlError::IoError => "500 Internal Server Error\n".to_string(),
-            FtlError::AllocateError => "500 Internal Server Error\n".to_string(),
-            FtlError::RingError => "400 HMAC Decode Error\n".to_string(),
-            FtlError::DecodeError => "400 HMAC Decode Error\n".to_string(),
-            FtlError::MissingPart => "400 Bad Request\n".to_string(),
-
-            FtlError::InvalidStreamKey => "405 Invalid stream key\n".to_string(),
-            FtlError::ChannelNotAuthorized => "401 Channel not authorized to stream\n".to_string(),
-            FtlError::ChannelInUse => "406 Channel actively streaming\n".to_string(),
-            FtlError::UnsupportedRegion => "407 Streaming from your region is not authorized\n".to_string(),
-            FtlError::GameBlocked => "409 Channel is not allowed to stream set game\n".to_string(),
-
-            FtlError::InvalidProtocolVersion => "400 Invalid Protocol Version\n".to_string(),
-            FtlError::UnsupportedProtocolVersion => "402 Outdated FTL SDK version\n".to_string(),
-            FtlError::MissingCodecInformation => "400 Missing Codec Information\n".to_string(),
-            FtlError::UnimplementedCommand => "901 Invalid Command\n".to_string(),
-            _ => "".to_string(),
+            FtlError::IoError(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FtlError {
+    fn from(error: io::Error) -> Self {
+        FtlError::IoError(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{FtlError, FtlResponse};
+
+    #[test]
+    fn should_map_codes() {
+        for code in [400, 401, 402, 403, 404, 405, 406, 407, 408, 409, 410, 500, 900, 901, 902, 903] {
+            let error = FtlError::from_code(code).unwrap();
+            assert_eq!(error.code(), Some(code));
         }
+
+        assert!(FtlError::from_code(200).is_none());
+        assert!(FtlError::Disconnect.code().is_none());
+    }
+
+    #[test]
+    fn should_respond() {
+        let resp = FtlError::InvalidStreamKey.to_response().unwrap();
+        assert_eq!(resp.to_string(), "405 Invalid stream key\n");
+        assert_eq!(resp, FtlResponse::Error { code: 405, message: "Invalid stream key".to_string() });
+
+        assert!(FtlError::Disconnect.to_response().is_none());
+    }
+
+    #[test]
+    fn should_describe_context() {
+        let error = FtlError::MissingCodecInformation { attribute: "VideoIngestSSRC" };
+        assert_eq!(error.to_string(), "missing codec information, VideoIngestSSRC was not given");
     }
 }
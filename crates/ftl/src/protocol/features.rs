@@ -0,0 +1,124 @@
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::iter::FromIterator;
+use std::str::FromStr;
+
+/// Protocol extension named in the `Features` attribute.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FtlFeature {
+    /// Media packets carry an HMAC so the ingest can drop forged packets.
+    SignedPackets,
+    /// Clients may resume a session after their control connection drops.
+    Roaming,
+    /// A feature this crate does not know about.
+    Other(String),
+}
+
+impl fmt::Display for FtlFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FtlFeature::SignedPackets => f.write_str("SignedPackets"),
+            FtlFeature::Roaming => f.write_str("Roaming"),
+            FtlFeature::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+impl FromStr for FtlFeature {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "SignedPackets" => FtlFeature::SignedPackets,
+            "Roaming" => FtlFeature::Roaming,
+            name => FtlFeature::Other(name.to_string()),
+        })
+    }
+}
+
+/// Set of features, written space separated as in `Features: SignedPackets Roaming`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FtlFeatures(BTreeSet<FtlFeature>);
+
+impl FtlFeatures {
+    pub fn new() -> FtlFeatures {
+        FtlFeatures::default()
+    }
+
+    pub fn insert(&mut self, feature: FtlFeature) -> bool {
+        self.0.insert(feature)
+    }
+
+    pub fn contains(&self, feature: &FtlFeature) -> bool {
+        self.0.contains(feature)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &FtlFeature> {
+        self.0.iter()
+    }
+
+    /// Features found in both sets, what a client asked for and the server supports.
+    pub fn intersection(&self, other: &FtlFeatures) -> FtlFeatures {
+        FtlFeatures(self.0.intersection(&other.0).cloned().collect())
+    }
+}
+
+impl FromIterator<FtlFeature> for FtlFeatures {
+    fn from_iter<I: IntoIterator<Item = FtlFeature>>(iter: I) -> Self {
+        FtlFeatures(iter.into_iter().collect())
+    }
+}
+
+impl fmt::Display for FtlFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, feature) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+
+            write!(f, "{}", feature)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for FtlFeatures {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.split_whitespace()
+            .map(|name| FtlFeature::from_str(name).unwrap_or_else(|never| match never {}))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{FtlFeature, FtlFeatures};
+
+    #[test]
+    fn should_parse_features() {
+        let features: FtlFeatures = "SignedPackets  Roaming Telepathy".parse().unwrap();
+
+        assert!(features.contains(&FtlFeature::SignedPackets));
+        assert!(features.contains(&FtlFeature::Roaming));
+        assert!(features.contains(&FtlFeature::Other("Telepathy".to_string())));
+        assert_eq!(features.to_string(), "SignedPackets Roaming Telepathy");
+
+        assert!("".parse::<FtlFeatures>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_intersect_features() {
+        let requested: FtlFeatures = "SignedPackets Telepathy".parse().unwrap();
+        let supported: FtlFeatures = "SignedPackets Roaming".parse().unwrap();
+
+        assert_eq!(requested.intersection(&supported).to_string(), "SignedPackets");
+    }
+}
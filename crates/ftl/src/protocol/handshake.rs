@@ -1,4 +1,7 @@
-use super::FtlError;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use super::{FtlCommand, FtlError, FtlFeatures, ProtocolVersion, VersionReq};
 
 #[derive(Default, Debug, Clone)]
 pub struct Vendor {
@@ -24,63 +27,77 @@ pub struct Audio {
 
 #[derive(Default, Debug, Clone)]
 pub struct FtlHandshake {
-    pub protocol_version: Option<(isize, isize)>,
+    pub protocol_version: Option<ProtocolVersion>,
     pub vendor: Vendor,
     pub video: Option<Video>,
     pub audio: Option<Audio>,
+    /// Features the client asked for, `None` if it didn't send a `Features` attribute.
+    pub features: Option<FtlFeatures>,
+    /// Attributes this crate does not recognise, such as vendor specific fields.
+    pub unknown_attributes: HashMap<String, String>,
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, FtlError> {
+    value.parse().map_err(|_| FtlError::InvalidAttribute {
+        key: key.to_string(),
+        value: value.to_string(),
+    })
+}
+
+fn parse_payload_type(key: &str, value: &str) -> Result<u8, FtlError> {
+    // RTP payload types are only 7 bits long.
+    match parse(key, value)? {
+        payload_type if payload_type < 128 => Ok(payload_type),
+        _ => Err(FtlError::InvalidAttribute {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+    }
 }
 
 impl FtlHandshake {
     /// Given a FTL attribute, insert it into the Handshake structure.
     pub fn insert(&mut self, key: String, value: String) -> Result<(), FtlError> {
-        // ! FIXME: causing panics here, try not doing that
         match key.as_ref() {
-            "ProtocolVersion" => {
-                let mut parts = value.split('.');
-                self.protocol_version = Some((
-                    parts.next().unwrap().parse().unwrap(),
-                    parts.next().unwrap().parse().unwrap()
-                ));
-            }
+            "ProtocolVersion" => self.protocol_version = Some(parse(&key, &value)?),
+            "Features" => self.features = Some(parse(&key, &value)?),
             "VendorName" => self.vendor.name = Some(value),
             "VendorVersion" => self.vendor.version = Some(value),
             "Video" |
-            "Audio" => match value.as_ref() {
-                "true" => {
-                    if key == "Video" {
-                        self.video = Some(Video::default());
-                    } else {
-                        self.audio = Some(Audio::default());
-                    }
-                },
-                "false" => {},
-                _ => panic!("Failed to deserialise boolean.")
+            "Audio" => if parse(&key, &value)? {
+                if key == "Video" {
+                    self.video = Some(Video::default());
+                } else {
+                    self.audio = Some(Audio::default());
+                }
             }
             "VideoCodec" |
             "VideoHeight" |
             "VideoWidth" |
             "VideoPayloadType" |
-            "VideoIngestSSRC" => if let Some(mut video) = self.video.as_mut() {
+            "VideoIngestSSRC" => if let Some(video) = self.video.as_mut() {
                 match key.as_ref() {
                     "VideoCodec" => video.codec = Some(value),
-                    "VideoHeight" => video.height = Some(value.parse().unwrap()),
-                    "VideoWidth" => video.width = Some(value.parse().unwrap()),
-                    "VideoPayloadType" => video.payload_type = Some(value.parse().unwrap()),
-                    "VideoIngestSSRC" => video.ssrc = Some(value.parse().unwrap()),
+                    "VideoHeight" => video.height = Some(parse(&key, &value)?),
+                    "VideoWidth" => video.width = Some(parse(&key, &value)?),
+                    "VideoPayloadType" => video.payload_type = Some(parse_payload_type(&key, &value)?),
+                    "VideoIngestSSRC" => video.ssrc = Some(parse(&key, &value)?),
                     _ => unreachable!()
                 }
             }
             "AudioCodec" |
             "AudioPayloadType" |
-            "AudioIngestSSRC" => if let Some(mut audio) = self.audio.as_mut() {
+            "AudioIngestSSRC" => if let Some(audio) = self.audio.as_mut() {
                 match key.as_ref() {
                     "AudioCodec" => audio.codec = Some(value),
-                    "AudioPayloadType" => audio.payload_type = Some(value.parse().unwrap()),
-                    "AudioIngestSSRC" => audio.ssrc = Some(value.parse().unwrap()),
+                    "AudioPayloadType" => audio.payload_type = Some(parse_payload_type(&key, &value)?),
+                    "AudioIngestSSRC" => audio.ssrc = Some(parse(&key, &value)?),
                     _ => unreachable!()
                 }
             }
-            _ => {}
+            _ => {
+                self.unknown_attributes.insert(key, value);
+            }
         }
 
         Ok(())
@@ -106,40 +123,112 @@ pub struct KnownAudio {
 
 #[derive(Debug, Clone)]
 pub struct FtlHandshakeFinalised {
-    pub protocol_version: (isize, isize),
+    pub protocol_version: ProtocolVersion,
     pub vendor: Vendor,
     pub video: Option<KnownVideo>,
     pub audio: Option<KnownAudio>,
+    /// Features in use for this stream.
+    ///
+    /// Once negotiated by a server these are the features both sides support,
+    /// otherwise they are what the client asked for.
+    pub features: FtlFeatures,
+    pub unknown_attributes: HashMap<String, String>,
+}
+
+impl FtlHandshakeFinalised {
+    /// Attributes a client sends to reproduce this handshake, in the order ftl-sdk sends them.
+    pub fn attributes(&self) -> Vec<FtlCommand> {
+        fn attribute(key: &str, value: impl ToString) -> FtlCommand {
+            FtlCommand::Attribute {
+                key: key.to_string(),
+                value: value.to_string(),
+            }
+        }
+
+        let mut attributes = vec![attribute("ProtocolVersion", &self.protocol_version)];
+
+        if !self.features.is_empty() {
+            attributes.push(attribute("Features", &self.features));
+        }
+
+        if let Some(name) = &self.vendor.name {
+            attributes.push(attribute("VendorName", name));
+        }
+
+        if let Some(version) = &self.vendor.version {
+            attributes.push(attribute("VendorVersion", version));
+        }
+
+        attributes.push(attribute("Video", self.video.is_some()));
+        if let Some(video) = &self.video {
+            attributes.push(attribute("VideoCodec", &video.codec));
+            attributes.push(attribute("VideoHeight", video.height));
+            attributes.push(attribute("VideoWidth", video.width));
+            attributes.push(attribute("VideoPayloadType", video.payload_type));
+            attributes.push(attribute("VideoIngestSSRC", video.ssrc));
+        }
+
+        attributes.push(attribute("Audio", self.audio.is_some()));
+        if let Some(audio) = &self.audio {
+            attributes.push(attribute("AudioCodec", &audio.codec));
+            attributes.push(attribute("AudioPayloadType", audio.payload_type));
+            attributes.push(attribute("AudioIngestSSRC", audio.ssrc));
+        }
+
+        for (key, value) in &self.unknown_attributes {
+            attributes.push(attribute(key, value));
+        }
+
+        attributes
+    }
 }
 
 impl FtlHandshake {
+    /// Verify the handshake, accepting the versions in [`ProtocolVersion::default_supported`].
     pub fn finalise(self) -> Result<FtlHandshakeFinalised, FtlError> {
-        Ok(FtlHandshakeFinalised {
-            protocol_version: if let Some((major, minor)) = self.protocol_version {
-                if major != 0 && minor != 9 {
-                    return Err(FtlError::UnsupportedProtocolVersion)
-                }
+        self.finalise_with(&ProtocolVersion::default_supported())
+    }
 
-                (major, minor)
-            } else {
-                return Err(FtlError::InvalidProtocolVersion)
+    /// Verify the handshake, accepting protocol versions within `supported`.
+    pub fn finalise_with(self, supported: &VersionReq) -> Result<FtlHandshakeFinalised, FtlError> {
+        let handshake = FtlHandshakeFinalised {
+            protocol_version: match self.protocol_version {
+                Some(version) if version.is_supported(supported) => version,
+                Some(_) => return Err(FtlError::UnsupportedProtocolVersion),
+                None => return Err(FtlError::InvalidProtocolVersion),
             },
             vendor: self.vendor,
             video: if let Some(video) = self.video {
-                match (video.codec, video.width, video.height, video.payload_type, video.ssrc) {
-                    (Some(codec), Some(width), Some(height), Some(payload_type), Some(ssrc)) =>
-                        Some(KnownVideo { codec, width, height, payload_type, ssrc }),
-                    _ => return Err(FtlError::MissingCodecInformation)
-                }
+                Some(KnownVideo {
+                    codec: video.codec.ok_or(FtlError::MissingCodecInformation { attribute: "VideoCodec" })?,
+                    width: video.width.ok_or(FtlError::MissingCodecInformation { attribute: "VideoWidth" })?,
+                    height: video.height.ok_or(FtlError::MissingCodecInformation { attribute: "VideoHeight" })?,
+                    payload_type: video.payload_type.ok_or(FtlError::MissingCodecInformation { attribute: "VideoPayloadType" })?,
+                    ssrc: video.ssrc.ok_or(FtlError::MissingCodecInformation { attribute: "VideoIngestSSRC" })?,
+                })
             } else { None },
             audio: if let Some(audio) = self.audio {
-                match (audio.codec, audio.payload_type, audio.ssrc) {
-                    (Some(codec), Some(payload_type), Some(ssrc)) =>
-                        Some(KnownAudio { codec, payload_type, ssrc }),
-                    _ => return Err(FtlError::MissingCodecInformation)
-                }
-            } else { None }
-        })
+                Some(KnownAudio {
+                    codec: audio.codec.ok_or(FtlError::MissingCodecInformation { attribute: "AudioCodec" })?,
+                    payload_type: audio.payload_type.ok_or(FtlError::MissingCodecInformation { attribute: "AudioPayloadType" })?,
+                    ssrc: audio.ssrc.ok_or(FtlError::MissingCodecInformation { attribute: "AudioIngestSSRC" })?,
+                })
+            } else { None },
+            features: self.features.unwrap_or_default(),
+            unknown_attributes: self.unknown_attributes,
+        };
+
+        // Both streams share a port, they can't share an SSRC.
+        if let (Some(video), Some(audio)) = (&handshake.video, &handshake.audio) {
+            if video.ssrc == audio.ssrc {
+                return Err(FtlError::InvalidAttribute {
+                    key: "AudioIngestSSRC".to_string(),
+                    value: audio.ssrc.to_string(),
+                });
+            }
+        }
+
+        Ok(handshake)
     }
 }
 //#endregion
@@ -172,6 +261,125 @@ mod tests {
         // correct, such as the protocol version and ensuring if A/V
         // streams are enabled that they have all fields present.
         let handshake = handshake.finalise().unwrap();
-        assert_eq!(handshake.protocol_version.1, 9);
+        assert_eq!(handshake.protocol_version.minor(), 9);
+    }
+
+    #[test]
+    fn should_check_protocol_version() {
+        use crate::protocol::{FtlError, FtlHandshake, VersionReq};
+
+        let handshake = |version: &str| {
+            let mut handshake = FtlHandshake::default();
+            handshake.insert("ProtocolVersion".to_string(), version.to_string()).unwrap();
+            handshake
+        };
+
+        assert!(handshake("0.9.1").finalise().is_ok());
+        assert!(handshake("0.1-features").finalise().is_ok());
+        assert!(matches!(handshake("1.9").finalise(), Err(FtlError::UnsupportedProtocolVersion)));
+        assert!(matches!(FtlHandshake::default().finalise(), Err(FtlError::InvalidProtocolVersion)));
+
+        let supported = VersionReq::parse("^0.9").unwrap();
+        assert!(handshake("0.9.1").finalise_with(&supported).is_ok());
+        assert!(matches!(handshake("0.1-features").finalise_with(&supported), Err(FtlError::UnsupportedProtocolVersion)));
+    }
+
+    #[test]
+    fn should_reject_shared_ssrc() {
+        use crate::protocol::{FtlError, FtlHandshake};
+
+        let mut handshake = FtlHandshake::default();
+        for (key, value) in [
+            ("ProtocolVersion", "0.9"),
+            ("Video", "true"),
+            ("VideoCodec", "H264"),
+            ("VideoHeight", "720"),
+            ("VideoWidth", "1280"),
+            ("VideoPayloadType", "96"),
+            ("VideoIngestSSRC", "77"),
+            ("Audio", "true"),
+            ("AudioCodec", "OPUS"),
+            ("AudioPayloadType", "97"),
+            ("AudioIngestSSRC", "77"),
+        ] {
+            handshake.insert(key.to_string(), value.to_string()).unwrap();
+        }
+
+        assert!(matches!(handshake.finalise(), Err(FtlError::InvalidAttribute { key, .. }) if key == "AudioIngestSSRC"));
+    }
+
+    #[test]
+    fn should_reject_invalid_attributes() {
+        use crate::protocol::{FtlError, FtlHandshake};
+
+        let mut handshake = FtlHandshake::default();
+        handshake.insert("Video".to_string(), "true".to_string()).unwrap();
+
+        for (key, value) in [
+            ("ProtocolVersion", "abc"),
+            ("ProtocolVersion", "0"),
+            ("Audio", "yes"),
+            ("VideoHeight", "abc"),
+            ("VideoPayloadType", "200"),
+            ("VideoIngestSSRC", "-1"),
+        ] {
+            match handshake.insert(key.to_string(), value.to_string()) {
+                Err(FtlError::InvalidAttribute { key: k, value: v }) => {
+                    assert_eq!(k, key);
+                    assert_eq!(v, value);
+                }
+                result => panic!("expected {} to be rejected, got {:?}", key, result),
+            }
+        }
+    }
+
+    #[test]
+    fn should_keep_unknown_attributes() {
+        use crate::protocol::FtlHandshake;
+
+        let mut handshake = FtlHandshake::default();
+        handshake.insert("ProtocolVersion".to_string(), "0.9".to_string()).unwrap();
+        handshake.insert("VendorBuild".to_string(), "1234".to_string()).unwrap();
+
+        let handshake = handshake.finalise().unwrap();
+        assert_eq!(handshake.unknown_attributes.get("VendorBuild").map(String::as_str), Some("1234"));
+    }
+
+    #[test]
+    fn should_round_trip_attributes() {
+        use crate::protocol::{FtlCommand, FtlHandshake};
+
+        let mut handshake = FtlHandshake::default();
+        for (key, value) in [
+            ("ProtocolVersion", "0.9"),
+            ("Features", "SignedPackets"),
+            ("VendorName", "OBS Studio"),
+            ("Video", "true"),
+            ("VideoCodec", "H264"),
+            ("VideoHeight", "720"),
+            ("VideoWidth", "1280"),
+            ("VideoPayloadType", "96"),
+            ("VideoIngestSSRC", "78"),
+            ("Audio", "false"),
+        ] {
+            handshake.insert(key.to_string(), value.to_string()).unwrap();
+        }
+
+        let finalised = handshake.finalise().unwrap();
+        let attributes = finalised.attributes();
+        assert_eq!(attributes[0].to_string(), "ProtocolVersion: 0.9");
+
+        let mut rebuilt = FtlHandshake::default();
+        for command in attributes {
+            if let FtlCommand::Attribute { key, value } = command {
+                rebuilt.insert(key, value).unwrap();
+            }
+        }
+
+        let rebuilt = rebuilt.finalise().unwrap();
+        assert_eq!(rebuilt.video.unwrap().ssrc, 78);
+        assert!(rebuilt.audio.is_none());
+        assert_eq!(rebuilt.vendor.name.as_deref(), Some("OBS Studio"));
+        assert_eq!(rebuilt.features.to_string(), "SignedPackets");
     }
 }
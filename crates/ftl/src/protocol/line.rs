@@ -0,0 +1,46 @@
+use super::FtlError;
+
+/// Find the next non-blank line buffered in `input`, shared by the session and the codecs.
+///
+/// Lines end in `\n` and every `\r` is ignored, which covers both the `\r\n` and the
+/// `\r\n\r\n` endings clients use. Returns the number of bytes to drop from the front
+/// of `input` along with the line, which is `None` until a whole line has arrived.
+pub(crate) fn next_line(input: &[u8], max_line_length: usize) -> Result<(usize, Option<Vec<u8>>), FtlError> {
+    let mut consumed = 0;
+
+    loop {
+        let remaining = &input[consumed..];
+        let position = match remaining.iter().position(|byte| *byte == b'\n') {
+            Some(position) => position,
+            // Don't keep buffering a line which will never be accepted.
+            None if remaining.len() > max_line_length => return Err(FtlError::LineTooLong),
+            None => return Ok((consumed, None)),
+        };
+
+        if position > max_line_length {
+            return Err(FtlError::LineTooLong);
+        }
+
+        let line: Vec<u8> = remaining[..position].iter()
+            .copied()
+            .filter(|byte| *byte != b'\r')
+            .collect();
+
+        consumed += position + 1;
+        if !line.is_empty() {
+            return Ok((consumed, Some(line)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::line::next_line;
+
+    #[test]
+    fn should_ignore_carriage_returns() {
+        let input = b"\r\n\r\nPING 7\r7\r\n\r\nHMAC";
+        assert_eq!(next_line(input, 1024).unwrap(), (14, Some(b"PING 77".to_vec())));
+        assert_eq!(next_line(&input[14..], 1024).unwrap(), (2, None));
+    }
+}
@@ -1,9 +1,19 @@
 mod command;
 mod error;
+mod features;
 mod handshake;
 mod response;
+mod version;
+
+#[cfg(any(feature = "session", feature = "codec", feature = "tokio-codec"))]
+mod line;
 
 pub use command::*;
 pub use error::*;
+pub use features::*;
 pub use handshake::*;
 pub use response::*;
+pub use version::*;
+
+#[cfg(any(feature = "session", feature = "codec", feature = "tokio-codec"))]
+pub(crate) use line::next_line;
@@ -1,31 +1,146 @@
-use std::string::ToString;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug)]
+use super::{FtlError, FtlFeatures};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum FtlResponse {
     HMAC { hmac_payload: String },
     Success,
-    Connect { udp_port: u16 },
+    /// `features` are those negotiated, only sent to clients which gave a `Features` attribute.
+    Connect { udp_port: u16, features: Option<FtlFeatures> },
     Pong,
+    /// Any non-success status code, the message may be empty.
+    Error { code: u16, message: String },
 }
 
-impl ToString for FtlResponse {
-    fn to_string(&self) -> String {
+impl fmt::Display for FtlResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            FtlResponse::HMAC { hmac_payload } => format!("200 {}\n", hmac_payload),
-            FtlResponse::Success => "200\n".to_string(),
-            FtlResponse::Connect { udp_port } => format!("200. Use UDP port {}\n", udp_port),
-            FtlResponse::Pong => "201\n".to_string(),
+            FtlResponse::HMAC { hmac_payload } => writeln!(f, "200 {}", hmac_payload),
+            FtlResponse::Success => f.write_str("200\n"),
+            FtlResponse::Connect { udp_port, features: None } => writeln!(f, "200. Use UDP port {}", udp_port),
+            FtlResponse::Connect { udp_port, features: Some(features) } =>
+                writeln!(f, "200. Use UDP port {}. Features: {}", udp_port, features),
+            FtlResponse::Pong => f.write_str("201\n"),
+            FtlResponse::Error { code, message } => {
+                if message.is_empty() {
+                    writeln!(f, "{}", code)
+                } else {
+                    writeln!(f, "{} {}", code, message)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for FtlResponse {
+    type Err = FtlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_end_matches(['\r', '\n']);
+        let (code, rest) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+
+        if code.len() != 3 {
+            return Err(FtlError::UnimplementedCommand);
+        }
+
+        match code {
+            "200" => {
+                if let Some(rest) = rest.strip_prefix('.') {
+                    let rest = rest
+                        .trim()
+                        .strip_prefix("Use UDP port ")
+                        .ok_or(FtlError::MissingPart)?;
+
+                    let (udp_port, features) = match rest.split_once(". Features:") {
+                        Some((udp_port, features)) => (udp_port, Some(features.parse().unwrap_or_default())),
+                        None => (rest, None),
+                    };
+
+                    Ok(FtlResponse::Connect {
+                        udp_port: udp_port.parse().map_err(|_| FtlError::MissingPart)?,
+                        features,
+                    })
+                } else if rest.is_empty() {
+                    Ok(FtlResponse::Success)
+                } else if let Some(hmac_payload) = rest.strip_prefix(' ') {
+                    Ok(FtlResponse::HMAC {
+                        hmac_payload: hmac_payload.trim().to_string(),
+                    })
+                } else {
+                    Err(FtlError::UnimplementedCommand)
+                }
+            }
+            "201" => Ok(FtlResponse::Pong),
+            code => Ok(FtlResponse::Error {
+                code: code.parse().map_err(|_| FtlError::UnimplementedCommand)?,
+                message: rest.trim().to_string(),
+            }),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::protocol::FtlResponse;
+    use std::str::FromStr;
+
+    use crate::protocol::{FtlFeature, FtlFeatures, FtlResponse};
 
     #[test]
     fn should_success() {
         let resp = FtlResponse::Success;
         assert_eq!(resp.to_string(), "200\n".to_string());
     }
+
+    #[test]
+    fn should_parse_hmac() {
+        let resp = FtlResponse::from_str("200 abcdef1234\n").unwrap();
+        assert_eq!(resp, FtlResponse::HMAC { hmac_payload: "abcdef1234".to_string() });
+    }
+
+    #[test]
+    fn should_parse_connect() {
+        let resp = FtlResponse::from_str("200. Use UDP port 65535\r\n").unwrap();
+        assert_eq!(resp, FtlResponse::Connect { udp_port: 65535, features: None });
+
+        let resp = FtlResponse::from_str("200. Use UDP port 65535. Features: SignedPackets\r\n").unwrap();
+        assert_eq!(resp, FtlResponse::Connect {
+            udp_port: 65535,
+            features: Some(vec![FtlFeature::SignedPackets].into_iter().collect()),
+        });
+    }
+
+    #[test]
+    fn should_parse_errors() {
+        let resp = FtlResponse::from_str("405 Invalid stream key\n").unwrap();
+        assert_eq!(resp, FtlResponse::Error { code: 405, message: "Invalid stream key".to_string() });
+
+        let resp = FtlResponse::from_str("903").unwrap();
+        assert_eq!(resp, FtlResponse::Error { code: 903, message: String::new() });
+    }
+
+    #[test]
+    fn should_fail_other() {
+        assert!(FtlResponse::from_str("").is_err());
+        assert!(FtlResponse::from_str("20").is_err());
+        assert!(FtlResponse::from_str("200x").is_err());
+        assert!(FtlResponse::from_str("200. Use UDP port abc").is_err());
+    }
+
+    #[test]
+    fn should_round_trip() {
+        for resp in [
+            FtlResponse::HMAC { hmac_payload: "abcdef".to_string() },
+            FtlResponse::Success,
+            FtlResponse::Connect { udp_port: 24000, features: None },
+            FtlResponse::Connect { udp_port: 24000, features: Some(FtlFeatures::new()) },
+            FtlResponse::Connect { udp_port: 24000, features: Some("SignedPackets Roaming".parse().unwrap()) },
+            FtlResponse::Pong,
+            FtlResponse::Error { code: 406, message: "Channel actively streaming".to_string() },
+            FtlResponse::Error { code: 400, message: String::new() },
+        ] {
+            assert_eq!(FtlResponse::from_str(&resp.to_string()).unwrap(), resp);
+        }
+    }
 }
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::str::FromStr;
+
+pub use semver::VersionReq;
+use semver::{Prerelease, Version};
+
+/// Value of the `ProtocolVersion` attribute, parsed as a semantic version.
+///
+/// ftl-sdk sends `{major}.{minor}`, so the patch version may be left out
+/// and defaults to zero. Pre-release tags such as `0.1-features` are kept.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion(Version);
+
+impl ProtocolVersion {
+    pub fn new(major: u64, minor: u64) -> ProtocolVersion {
+        ProtocolVersion(Version::new(major, minor, 0))
+    }
+
+    pub fn major(&self) -> u64 {
+        self.0.major
+    }
+
+    pub fn minor(&self) -> u64 {
+        self.0.minor
+    }
+
+    pub fn patch(&self) -> u64 {
+        self.0.patch
+    }
+
+    /// Pre-release tag, such as `features` in `0.1-features`, empty if there is none.
+    pub fn pre(&self) -> &str {
+        self.0.pre.as_str()
+    }
+
+    /// Versions accepted by [`FtlHandshake::finalise`], any `0.x` release.
+    ///
+    /// [`FtlHandshake::finalise`]: super::FtlHandshake::finalise
+    pub fn default_supported() -> VersionReq {
+        VersionReq::parse(">=0.1, <1").expect("valid version requirement")
+    }
+
+    pub fn as_semver(&self) -> &Version {
+        &self.0
+    }
+
+    /// Whether the version falls within `supported`.
+    ///
+    /// Pre-release tags name protocol extensions rather than unstable releases,
+    /// so they are ignored here: `0.1-features` is supported if `0.1` is.
+    pub fn is_supported(&self, supported: &VersionReq) -> bool {
+        supported.matches(&Version {
+            pre: Prerelease::EMPTY,
+            ..self.0.clone()
+        })
+    }
+}
+
+impl From<Version> for ProtocolVersion {
+    fn from(version: Version) -> Self {
+        ProtocolVersion(version)
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = semver::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Fill in the patch version before handing it to semver.
+        let (core, rest) = match s.find(['-', '+']) {
+            Some(position) => s.split_at(position),
+            None => (s, ""),
+        };
+
+        let version = match core.split('.').count() {
+            2 => format!("{}.0{}", core, rest),
+            _ => s.to_string(),
+        };
+
+        Version::parse(&version).map(ProtocolVersion)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    /// Written as `{major}.{minor}` unless there is a patch version, which keeps ftl-sdk happy.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.0.major, self.0.minor)?;
+
+        if self.0.patch != 0 {
+            write!(f, ".{}", self.0.patch)?;
+        }
+
+        if !self.0.pre.is_empty() {
+            write!(f, "-{}", self.0.pre)?;
+        }
+
+        if !self.0.build.is_empty() {
+            write!(f, "+{}", self.0.build)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{ProtocolVersion, VersionReq};
+
+    #[test]
+    fn should_parse_versions() {
+        let version: ProtocolVersion = "0.9".parse().unwrap();
+        assert_eq!((version.major(), version.minor(), version.patch()), (0, 9, 0));
+        assert_eq!(version, ProtocolVersion::new(0, 9));
+
+        let version: ProtocolVersion = "0.9.1".parse().unwrap();
+        assert_eq!(version.patch(), 1);
+
+        let version: ProtocolVersion = "0.1-features".parse().unwrap();
+        assert_eq!((version.major(), version.minor(), version.pre()), (0, 1, "features"));
+
+        for invalid in ["0", "abc", "0.9.1.2", "0.-1", "0.9-", ""] {
+            assert!(invalid.parse::<ProtocolVersion>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn should_display_versions() {
+        for version in ["0.9", "0.9.1", "0.1-features", "1.2.3-beta.1"] {
+            assert_eq!(version.parse::<ProtocolVersion>().unwrap().to_string(), version);
+        }
+    }
+
+    #[test]
+    fn should_check_support() {
+        let supported = VersionReq::parse(">=0.9, <0.11").unwrap();
+
+        assert!("0.9".parse::<ProtocolVersion>().unwrap().is_supported(&supported));
+        assert!("0.10.2".parse::<ProtocolVersion>().unwrap().is_supported(&supported));
+        assert!("0.9-features".parse::<ProtocolVersion>().unwrap().is_supported(&supported));
+        assert!(!"0.1".parse::<ProtocolVersion>().unwrap().is_supported(&supported));
+        assert!(!"1.9".parse::<ProtocolVersion>().unwrap().is_supported(&supported));
+    }
+}
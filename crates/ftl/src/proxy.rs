@@ -0,0 +1,201 @@
+//! HAProxy PROXY protocol header parsing, version 1 and 2.
+//!
+//! Load balancers in front of the control listener can prepend one of these
+//! headers to every connection so the server learns the client's real address.
+//! See <https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt>.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyHeader {
+    /// Connection was proxied on behalf of a client.
+    Proxied {
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    /// Connection was made by the proxy itself, such as a health check,
+    /// or the proxy did not know the addresses.
+    Local,
+}
+
+impl ProxyHeader {
+    /// Address of the original client, if known.
+    pub fn source(&self) -> Option<SocketAddr> {
+        match self {
+            ProxyHeader::Proxied { source, .. } => Some(*source),
+            ProxyHeader::Local => None,
+        }
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parse a PROXY protocol header from the start of `buffer`.
+///
+/// Returns `Ok(None)` if more data is needed, otherwise the header and how many bytes it took up.
+pub fn parse(buffer: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buffer.starts_with(V2_SIGNATURE) {
+        parse_v2(buffer)
+    } else if buffer.starts_with(V1_PREFIX) {
+        parse_v1(buffer)
+    } else if V2_SIGNATURE.starts_with(buffer) || V1_PREFIX.starts_with(buffer) {
+        Ok(None)
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+fn parse_v1(buffer: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    let end = match buffer.windows(2).position(|window| window == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LENGTH => end,
+        None if buffer.len() < V1_MAX_LENGTH => return Ok(None),
+        _ => return Err(invalid("PROXY v1 header is too long")),
+    };
+
+    let line = std::str::from_utf8(&buffer[..end]).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let mut parts = line.split(' ').skip(1);
+
+    let header = match parts.next() {
+        Some("UNKNOWN") => ProxyHeader::Local,
+        Some(protocol @ "TCP4") | Some(protocol @ "TCP6") => {
+            let mut next = || parts.next().ok_or_else(|| invalid("PROXY v1 header is missing a part"));
+            let source: IpAddr = next()?.parse().map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let destination: IpAddr = next()?.parse().map_err(|_| invalid("invalid PROXY v1 destination address"))?;
+            let source_port: u16 = next()?.parse().map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            let destination_port: u16 = next()?.parse().map_err(|_| invalid("invalid PROXY v1 destination port"))?;
+
+            if parts.next().is_some() {
+                return Err(invalid("PROXY v1 header has too many parts"));
+            }
+
+            if source.is_ipv4() != (protocol == "TCP4") || destination.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match protocol"));
+            }
+
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(source, source_port),
+                destination: SocketAddr::new(destination, destination_port),
+            }
+        }
+        _ => return Err(invalid("unknown PROXY v1 protocol")),
+    };
+
+    Ok(Some((header, end + 2)))
+}
+
+fn parse_v2(buffer: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buffer.len() < V2_HEADER_LENGTH {
+        return Ok(None);
+    }
+
+    let version_command = buffer[12];
+    let family = buffer[13];
+    let length = V2_HEADER_LENGTH + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    if buffer.len() < length {
+        return Ok(None);
+    }
+
+    let addresses = &buffer[V2_HEADER_LENGTH..length];
+    let header = match (version_command & 0x0F, family) {
+        (0x0, _) => ProxyHeader::Local,
+        // TCP over IPv4
+        (0x1, 0x11) if addresses.len() >= 12 => {
+            let ip = |at: usize| IpAddr::V4(Ipv4Addr::new(addresses[at], addresses[at + 1], addresses[at + 2], addresses[at + 3]));
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            }
+        }
+        // TCP over IPv6
+        (0x1, 0x21) if addresses.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0_u8; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            }
+        }
+        (0x1, 0x11) | (0x1, 0x21) => return Err(invalid("PROXY v2 address block is too short")),
+        // Other families (UDP, UNIX sockets, unspecified) don't tell us anything useful.
+        (0x1, _) => ProxyHeader::Local,
+        _ => return Err(invalid("unknown PROXY v2 command")),
+    };
+
+    Ok(Some((header, length)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::{parse, ProxyHeader};
+
+    #[test]
+    fn should_parse_v1() {
+        let data = b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 8084\r\nHMAC\r\n\r\n";
+        let (header, length) = parse(data).unwrap().unwrap();
+
+        assert_eq!(header.source(), Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(&data[length..], b"HMAC\r\n\r\n");
+
+        let (header, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 8084\r\n").unwrap().unwrap();
+        assert_eq!(header.source(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (header, _) = parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(header, ProxyHeader::Local);
+    }
+
+    #[test]
+    fn should_parse_v2() {
+        let mut data = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        data.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xdc, 0x04, 0x1f, 0x94]);
+        data.extend_from_slice(b"HMAC\r\n\r\n");
+
+        let (header, length) = parse(&data).unwrap().unwrap();
+        assert_eq!(header, ProxyHeader::Proxied {
+            source: "203.0.113.7:56324".parse().unwrap(),
+            destination: "10.0.0.1:8084".parse().unwrap(),
+        });
+        assert_eq!(&data[length..], b"HMAC\r\n\r\n");
+
+        let (header, length) = parse(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00").unwrap().unwrap();
+        assert_eq!(header, ProxyHeader::Local);
+        assert_eq!(length, 16);
+    }
+
+    #[test]
+    fn should_wait_for_more_data() {
+        assert!(parse(b"").unwrap().is_none());
+        assert!(parse(b"PRO").unwrap().is_none());
+        assert!(parse(b"PROXY TCP4 203.0.113.7").unwrap().is_none());
+        assert!(parse(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xcb").unwrap().is_none());
+    }
+
+    #[test]
+    fn should_reject_invalid_headers() {
+        assert!(parse(b"HMAC\r\n\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 2001:db8::1 10.0.0.1 56324 8084\r\n").is_err());
+        assert!(parse(b"PROXY TCP5 203.0.113.7 10.0.0.1 56324 8084\r\n").is_err());
+        assert!(parse(&[b'P', b'R', b'O', b'X', b'Y', b' '].repeat(20)).is_err());
+        assert!(parse(b"\r\n\r\n\0\r\nQUIT\n\x11\x11\x00\x00").is_err());
+    }
+}
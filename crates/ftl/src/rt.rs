@@ -0,0 +1,88 @@
+//! Runtime specific primitives used by the server and client.
+//!
+//! async-std is used by default, enabling the `tokio` feature switches
+//! everything over to tokio, even if `server` is also enabled.
+
+use std::future::Future;
+#[cfg(any(feature = "server", feature = "tokio"))]
+use std::task::Poll;
+use std::time::Duration;
+
+#[cfg(not(feature = "tokio"))]
+pub use async_std::io::prelude::{ReadExt as AsyncReadExt, WriteExt as AsyncWriteExt};
+#[cfg(not(feature = "tokio"))]
+pub use async_std::net::TcpStream;
+#[cfg(all(feature = "server", not(feature = "tokio")))]
+pub use async_std::net::TcpListener;
+#[cfg(all(feature = "client", not(feature = "tokio")))]
+pub use async_std::net::UdpSocket;
+#[cfg(all(feature = "server", not(feature = "tokio")))]
+pub use async_std::task::spawn;
+
+#[cfg(feature = "tokio")]
+pub use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "tokio")]
+pub use tokio::net::{TcpListener, TcpStream};
+#[cfg(all(feature = "client", feature = "tokio"))]
+pub use tokio::net::UdpSocket;
+#[cfg(feature = "tokio")]
+pub use tokio::spawn;
+
+// Tests use `#[rt::test]` so they run on whichever runtime is enabled.
+#[cfg(all(test, feature = "server", not(feature = "tokio")))]
+pub use async_std::test;
+#[cfg(all(test, feature = "server", not(feature = "tokio")))]
+pub use async_std::task::{sleep, JoinHandle};
+
+#[cfg(all(test, feature = "tokio"))]
+pub use tokio::test;
+#[cfg(all(test, feature = "tokio"))]
+pub use tokio::{task::JoinHandle, time::sleep};
+
+/// Wait for a spawned task to finish, panicking if it did.
+#[cfg(all(test, feature = "server", not(feature = "tokio")))]
+pub async fn join<T>(handle: JoinHandle<T>) -> T {
+    handle.await
+}
+
+/// Wait for a spawned task to finish, panicking if it did.
+#[cfg(all(test, feature = "tokio"))]
+pub async fn join<T>(handle: JoinHandle<T>) -> T {
+    handle.await.expect("task panicked")
+}
+
+/// Run a future, giving up after `duration` has elapsed.
+#[cfg(not(feature = "tokio"))]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    async_std::future::timeout(duration, future).await.ok()
+}
+
+/// Run a future, giving up after `duration` has elapsed.
+#[cfg(feature = "tokio")]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    tokio::time::timeout(duration, future).await.ok()
+}
+
+/// Wait for whichever future finishes first, dropping the other.
+#[cfg(any(feature = "server", feature = "tokio"))]
+pub async fn race<T>(a: impl Future<Output = T>, b: impl Future<Output = T>) -> T {
+    let mut a = std::pin::pin!(a);
+    let mut b = std::pin::pin!(b);
+
+    std::future::poll_fn(|cx| match a.as_mut().poll(cx) {
+        Poll::Ready(output) => Poll::Ready(output),
+        Poll::Pending => b.as_mut().poll(cx),
+    }).await
+}
+
+/// Close both halves of the stream, ignoring any errors.
+#[cfg(not(feature = "tokio"))]
+pub async fn shutdown(stream: &mut TcpStream) {
+    stream.shutdown(std::net::Shutdown::Both).ok();
+}
+
+/// Close the stream, ignoring any errors.
+#[cfg(feature = "tokio")]
+pub async fn shutdown(stream: &mut TcpStream) {
+    stream.shutdown().await.ok();
+}
@@ -1,181 +1,1637 @@
-use std::str::FromStr;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
 
-use async_std::{io, task};
-use async_std::prelude::*;
 use async_trait::async_trait;
-use async_std::net::{TcpListener, TcpStream};
-use async_std::channel::{Receiver, bounded};
+use async_channel::{Receiver, Sender, bounded};
 
 use log::{debug, error, info, trace};
 
-use crate::protocol::{FtlCommand, FtlError, FtlHandshake, FtlHandshakeFinalised, FtlResponse};
-use crate::util;
+use crate::protocol::{FtlError, FtlFeature, FtlFeatures, FtlHandshakeFinalised, ProtocolVersion, VersionReq};
+use crate::proxy::{self, ProxyHeader};
+use crate::rt::{self, AsyncReadExt, AsyncWriteExt, TcpListener, TcpStream};
+use crate::session::{FtlLimits, FtlSession, FtlSessionEvent, StreamKey};
+use crate::signing::PacketKey;
 
-pub struct IngestClient {
+/// What to do when a client finishes the handshake for a channel which is already streaming.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelPolicy {
+    /// Refuse the new client with `406 Channel actively streaming`.
+    Reject,
+    /// Terminate the existing session with `410 Server terminated stream` and
+    /// let the new client stream, useful for encoders reconnecting after a crash.
+    TakeOver,
+}
+
+/// Settings applied to every connection accepted by an [`IngestServer`].
+#[derive(Debug, Clone)]
+pub struct IngestConfig {
+    pub limits: FtlLimits,
+    /// Protocol versions clients may use, see [`ProtocolVersion::is_supported`].
+    pub supported_versions: VersionReq,
+    /// Protocol extensions this server supports, clients only get those they ask for.
+    pub supported_features: FtlFeatures,
+    pub channel_policy: ChannelPolicy,
+    /// Time a client has to authenticate and finish the handshake.
+    pub handshake_timeout: Duration,
+    /// Time a streaming client may go without sending anything, usually a `PING`.
+    ///
+    /// Clients which go quiet are disconnected with `903 Internal Socket Timeout`.
+    pub idle_timeout: Duration,
+    /// Time a stream may go without media before being disconnected with `408 No media received`.
+    ///
+    /// Only enforced if set, the ingest must report media through [`MediaActivity::touch`].
+    pub media_timeout: Option<Duration>,
+    /// Expect a PROXY protocol v1 or v2 header before the first FTL line, for use behind a load balancer.
+    ///
+    /// The client address from the header is then used in place of the proxy's.
+    /// Connections without a valid header are dropped, so only enable this if every
+    /// connection comes through the proxy.
+    pub proxy_protocol: bool,
+    /// Limit connections and failed `CONNECT` attempts per address, temporarily banning those which go over.
+    pub rate_limits: Option<RateLimits>,
+    /// Time a roaming client has to `RESUME` after its control connection drops.
+    ///
+    /// Only applies to streams which negotiated [`FtlFeature::Roaming`], add it to
+    /// `supported_features` to offer it. The stream and its UDP port are kept until then.
+    pub resume_timeout: Duration,
+    /// Set if every stream's media arrives on the same UDP port, streams are then told apart by SSRC.
+    ///
+    /// Handshakes whose SSRCs are in use by another live stream are refused before
+    /// [`IngestServer::allocate_ingest`] is called. Otherwise SSRCs are only checked
+    /// against streams which were allocated the same port, once it's known.
+    pub shared_media_port: bool,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            limits: FtlLimits::default(),
+            supported_versions: ProtocolVersion::default_supported(),
+            supported_features: FtlFeatures::default(),
+            channel_policy: ChannelPolicy::Reject,
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(30),
+            media_timeout: None,
+            proxy_protocol: false,
+            rate_limits: None,
+            resume_timeout: Duration::from_secs(10),
+            shared_media_port: false,
+        }
+    }
+}
+
+/// Per address limits on connections and failed authentication attempts.
+///
+/// Addresses going over either limit are banned, banned addresses have their
+/// connections dropped before an HMAC payload is issued. IPv6 addresses are
+/// limited per /64, since a single host usually has a whole /64 to pick from.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Connections allowed from one address per `window`.
+    pub max_connections: u32,
+    /// Failed `CONNECT` attempts allowed from one address per `window`.
+    pub max_failed_attempts: u32,
+    pub window: Duration,
+    /// Length of an address's first ban, each ban after it is twice as long.
+    pub ban_duration: Duration,
+    /// Longest ban given out. Addresses which stay quiet for this long have their previous bans forgotten.
+    pub max_ban_duration: Duration,
+    /// Most addresses tracked at once. While the table is full, the oldest address which isn't
+    /// banned is forgotten to make room for a new one, which is only refused if all of them are.
+    pub max_tracked_addresses: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            max_connections: 30,
+            max_failed_attempts: 5,
+            window: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(30),
+            max_ban_duration: Duration::from_secs(60 * 60),
+            max_tracked_addresses: 100_000,
+        }
+    }
+}
+
+/// Counters for monitoring the rate limiter, see [`IngestHandle::rate_limit_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitStats {
+    /// Connections dropped because their address was banned, or every tracked address is.
+    pub refused_connections: u64,
+    /// `CONNECT` attempts with an invalid stream key or HMAC.
+    pub failed_attempts: u64,
+    /// Bans given out, including repeat bans.
+    pub bans: u64,
+    /// Addresses which are banned right now.
+    pub banned_addresses: usize,
+    /// Records forgotten early to make room for a new address, see [`RateLimits::max_tracked_addresses`].
+    pub evicted_addresses: u64,
+}
+
+struct AddressRecord {
+    window_start: Instant,
+    connections: u32,
+    failures: u32,
+    /// Number of times this address has been banned, doubles each ban.
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+impl AddressRecord {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    fn ban(&mut self, limits: &RateLimits, now: Instant) -> Duration {
+        let duration = limits.ban_duration
+            .saturating_mul(2_u32.saturating_pow(self.strikes))
+            .min(limits.max_ban_duration);
+
+        self.strikes += 1;
+        self.banned_until = Some(now + duration);
+        self.window_start = now;
+        self.connections = 0;
+        self.failures = 0;
+        duration
+    }
+}
+
+#[derive(Default)]
+struct RateLimiter {
+    addresses: HashMap<IpAddr, AddressRecord>,
+    pruned_at: Option<Instant>,
+    stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    /// The address records are kept under, IPv6 addresses are grouped by /64.
+    fn key(ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+            },
+            ip => ip,
+        }
+    }
+
+    /// Forget addresses which are neither banned nor have been seen recently, at most once per window.
+    fn prune(&mut self, limits: &RateLimits, now: Instant) {
+        if self.pruned_at.is_some_and(|pruned_at| now.duration_since(pruned_at) < limits.window) {
+            return;
+        }
+
+        self.addresses.retain(|_, record| record.is_banned(now) || now.duration_since(record.window_start) < limits.max_ban_duration);
+        self.pruned_at = Some(now);
+    }
+
+    /// Find the record for `key`, making room by forgetting the oldest address which isn't banned.
+    ///
+    /// Returns `None` if the table is full of banned addresses.
+    fn record(&mut self, key: IpAddr, limits: &RateLimits, now: Instant) -> Option<&mut AddressRecord> {
+        self.prune(limits, now);
+        if !self.addresses.contains_key(&key) && self.addresses.len() >= limits.max_tracked_addresses {
+            let oldest = self.addresses.iter()
+                .filter(|(_, record)| !record.is_banned(now))
+                .min_by_key(|(_, record)| record.window_start)
+                .map(|(oldest, _)| *oldest)?;
+
+            self.addresses.remove(&oldest);
+            self.stats.evicted_addresses += 1;
+        }
+
+        let record = self.addresses.entry(key).or_insert_with(|| AddressRecord {
+            window_start: now,
+            connections: 0,
+            failures: 0,
+            strikes: 0,
+            banned_until: None,
+        });
+
+        if now.duration_since(record.window_start) >= limits.window {
+            record.window_start = now;
+            record.connections = 0;
+            record.failures = 0;
+        }
+
+        Some(record)
+    }
+
+    /// Count a new connection, returns `false` if it should be dropped.
+    fn allow_connection(&mut self, ip: IpAddr, limits: &RateLimits, now: Instant) -> bool {
+        let key = RateLimiter::key(ip);
+        match self.record(key, limits, now) {
+            Some(record) if !record.is_banned(now) => {
+                record.connections += 1;
+                if record.connections <= limits.max_connections {
+                    return true;
+                }
+
+                let duration = record.ban(limits, now);
+                info!("Banned {} for {:?}, too many connections.", key, duration);
+                self.stats.bans += 1;
+            }
+            Some(_) => {}
+            None => debug!("Refused connection from {}, too many banned addresses tracked.", ip),
+        }
+
+        self.stats.refused_connections += 1;
+        false
+    }
+
+    fn record_failure(&mut self, ip: IpAddr, limits: &RateLimits, now: Instant) {
+        self.stats.failed_attempts += 1;
+
+        let key = RateLimiter::key(ip);
+        if let Some(record) = self.record(key, limits, now) {
+            record.failures += 1;
+            if record.failures >= limits.max_failed_attempts {
+                let duration = record.ban(limits, now);
+                info!("Banned {} for {:?}, too many failed attempts.", key, duration);
+                self.stats.bans += 1;
+            }
+        }
+    }
+}
+
+/// Records when media was last received for a stream.
+#[derive(Debug, Clone)]
+pub struct MediaActivity(Arc<Mutex<Instant>>);
+
+impl MediaActivity {
+    fn new() -> MediaActivity {
+        MediaActivity(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Note that media was just received.
+    pub fn touch(&self) {
+        if let Ok(mut last) = self.0.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    /// Time since media was last received, or since the stream started.
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().map(|last| last.elapsed()).unwrap_or_default()
+    }
+}
+
+struct SessionEntry {
     channel_id: Option<String>,
-    hmac_payload: String,
-    handshake: FtlHandshake,
-    stop_signal: Receiver<()>
+    streaming: bool,
+    /// UDP port and SSRCs in use, the port is `None` if it's shared by every stream.
+    media: Option<(Option<u16>, Option<u32>, Option<u32>)>,
+    stop: Sender<()>,
+    terminate: Sender<FtlError>,
+    /// Closed once the session's task has finished.
+    done: Receiver<()>,
+}
+
+/// A roaming session whose control connection dropped, waiting to be resumed.
+struct SuspendedSession {
+    registration: Registration,
+    info: SessionInfo,
+    activity: MediaActivity,
+    /// Closed once another connection has taken the session over.
+    resumed: Sender<()>,
+}
+
+struct Registration {
+    id: u64,
+    stop_signal: Receiver<()>,
+    terminated: Receiver<FtlError>,
+    /// Dropped when the session's task finishes.
+    _finished: Sender<()>,
+}
+
+/// Controls a running [`IngestServer`], clones refer to the same server.
+#[derive(Clone)]
+pub struct IngestHandle {
+    closing: Sender<()>,
+    closed: Receiver<()>,
+    sessions: Arc<Mutex<HashMap<u64, SessionEntry>>>,
+    suspended: Arc<Mutex<HashMap<u64, SuspendedSession>>>,
+    next_id: Arc<AtomicU64>,
+    exited: (Sender<()>, Receiver<()>),
+    limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl Default for IngestHandle {
+    fn default() -> Self {
+        IngestHandle::new()
+    }
+}
+
+impl IngestHandle {
+    pub fn new() -> IngestHandle {
+        let (closing, closed) = bounded(1);
+
+        IngestHandle {
+            closing,
+            closed,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            suspended: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            exited: bounded(1),
+            limiter: Arc::new(Mutex::new(RateLimiter::default())),
+        }
+    }
+
+    /// Stop accepting connections and let the remaining sessions drain.
+    ///
+    /// Streams carry on for up to `deadline`, each stop signal fires as its session ends.
+    /// Sessions still open after that are terminated with `410 Server terminated stream`,
+    /// and this returns once they have all closed.
+    pub async fn shutdown(&self, deadline: Duration) {
+        info!("Shutting down FTL ingest server.");
+        self.closing.close();
+
+        if rt::timeout(deadline, self.drained()).await.is_none() {
+            info!("Terminating remaining FTL sessions.");
+            for entry in self.entries().values() {
+                entry.terminate.try_send(FtlError::ServerTerminate).ok();
+            }
+
+            self.drained().await;
+        }
+    }
+
+    /// End the sessions authenticated for this channel, the error is sent to the client
+    /// and each session is torn down as if the client had disconnected.
+    ///
+    /// Use [`FtlError::ServerTerminate`] (410), [`FtlError::ChannelNotAuthorized`] (401)
+    /// or [`FtlError::GameBlocked`] (409). Returns `false` if the channel had no session.
+    pub fn terminate(&self, channel_id: &str, error: FtlError) -> bool {
+        let sessions: Vec<Sender<FtlError>> = self.entries().values()
+            .filter(|entry| entry.channel_id.as_deref() == Some(channel_id))
+            .map(|entry| entry.terminate.clone())
+            .collect();
+
+        let (first, rest) = match sessions.split_first() {
+            Some(sessions) => sessions,
+            None => return false,
+        };
+
+        // Errors can't be cloned, any other sessions get one with the same status code.
+        for session in rest {
+            let error = error.code().and_then(FtlError::from_code).unwrap_or(FtlError::ServerTerminate);
+            session.try_send(error).ok();
+        }
+
+        first.try_send(error).ok();
+        true
+    }
+
+    /// Channels with an authenticated session.
+    pub fn channels(&self) -> Vec<String> {
+        self.entries().values()
+            .filter_map(|entry| entry.channel_id.clone())
+            .collect()
+    }
+
+    /// Whether a session is streaming to this channel.
+    pub fn is_streaming(&self, channel_id: &str) -> bool {
+        self.entries().values()
+            .any(|entry| entry.streaming && entry.channel_id.as_deref() == Some(channel_id))
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.closing.is_closed()
+    }
+
+    /// Connections and authentication attempts blocked by [`IngestConfig::rate_limits`].
+    pub fn rate_limit_stats(&self) -> RateLimitStats {
+        let limiter = self.limiter();
+        let now = Instant::now();
+
+        RateLimitStats {
+            banned_addresses: limiter.addresses.values().filter(|record| record.is_banned(now)).count(),
+            ..limiter.stats.clone()
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<u64, SessionEntry>> {
+        self.sessions.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn limiter(&self) -> MutexGuard<'_, RateLimiter> {
+        self.limiter.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn suspended(&self) -> MutexGuard<'_, HashMap<u64, SuspendedSession>> {
+        self.suspended.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn suspend(&self, session: SuspendedSession) {
+        self.suspended().insert(session.registration.id, session);
+    }
+
+    /// Take over the suspended session streaming to this channel, if there is one.
+    fn resume(&self, channel_id: &str) -> Option<SuspendedSession> {
+        let mut suspended = self.suspended();
+        let id = suspended.iter()
+            .find(|(_, session)| session.info.channel_id.as_deref() == Some(channel_id))
+            .map(|(id, _)| *id)?;
+
+        suspended.remove(&id)
+    }
+
+    /// Take back a suspended session which was not resumed.
+    fn expire(&self, id: u64) -> Option<SuspendedSession> {
+        self.suspended().remove(&id)
+    }
+
+    /// Pass a termination on to a session, used if it was resumed while the error was in flight.
+    fn forward_terminate(&self, id: u64, error: FtlError) {
+        if let Some(entry) = self.entries().get(&id) {
+            entry.terminate.try_send(error).ok();
+        }
+    }
+
+    fn register(&self) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (stop, stop_signal) = bounded(1);
+        let (terminate, terminated) = bounded(1);
+        let (finished, done) = bounded(1);
+
+        if self.is_shutting_down() {
+            stop.close();
+        }
+
+        self.entries().insert(id, SessionEntry {
+            channel_id: None,
+            streaming: false,
+            media: None,
+            stop,
+            terminate,
+            done,
+        });
+
+        Registration {
+            id,
+            stop_signal,
+            terminated,
+            _finished: finished,
+        }
+    }
+
+    /// Mark the session as streaming to its channel, unless another session already is.
+    ///
+    /// Returns the other session's terminate sender and a receiver which closes once it's gone.
+    fn claim(&self, id: u64, channel_id: &str) -> Option<(Sender<FtlError>, Receiver<()>)> {
+        let mut entries = self.entries();
+        let existing = entries.iter()
+            .find(|(other, entry)| **other != id && entry.streaming && entry.channel_id.as_deref() == Some(channel_id))
+            .map(|(_, entry)| (entry.terminate.clone(), entry.done.clone()));
+
+        if existing.is_none() {
+            if let Some(entry) = entries.get_mut(&id) {
+                entry.streaming = true;
+            }
+        }
+
+        existing
+    }
+
+    fn set_channel(&self, id: u64, channel_id: &str) {
+        if let Some(entry) = self.entries().get_mut(&id) {
+            entry.channel_id = Some(channel_id.to_string());
+        }
+    }
+
+    /// Record the session's media port and SSRCs, unless another session
+    /// receiving media on the same port already uses one of the SSRCs.
+    ///
+    /// A port of `None` means every stream shares one, so all sessions are checked.
+    fn claim_ssrcs(&self, id: u64, udp_port: Option<u16>, video: Option<u32>, audio: Option<u32>) -> Result<(), FtlError> {
+        let mut entries = self.entries();
+        let in_use: Vec<u32> = entries.iter()
+            .filter(|(other, _)| **other != id)
+            .filter_map(|(_, entry)| entry.media)
+            .filter(|(port, _, _)| udp_port.is_none() || *port == udp_port)
+            .flat_map(|(_, video, audio)| video.into_iter().chain(audio))
+            .collect();
+
+        if video.is_some_and(|ssrc| in_use.contains(&ssrc)) {
+            return Err(FtlError::VideoSsrcCollision);
+        }
+
+        if audio.is_some_and(|ssrc| in_use.contains(&ssrc)) {
+            return Err(FtlError::AudioSsrcCollision);
+        }
+
+        if let Some(entry) = entries.get_mut(&id) {
+            entry.media = Some((udp_port, video, audio));
+        }
+
+        Ok(())
+    }
+
+    fn unregister(&self, id: u64) {
+        if let Some(entry) = self.entries().remove(&id) {
+            entry.stop.close();
+        }
+
+        self.exited.0.try_send(()).ok();
+    }
+
+    async fn drained(&self) {
+        while !self.entries().is_empty() {
+            self.exited.1.recv().await.ok();
+        }
+    }
+}
+
+/// Read a PROXY protocol header, returning it along with any bytes read past it.
+async fn read_proxy_header(stream: &mut TcpStream) -> io::Result<(ProxyHeader, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0_u8; 256];
+
+    loop {
+        if let Some((header, length)) = proxy::parse(&buffer)? {
+            buffer.drain(..length);
+            return Ok((header, buffer));
+        }
+
+        match stream.read(&mut chunk).await? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => buffer.extend_from_slice(&chunk[..read]),
+        }
+    }
+}
+
+enum Wake {
+    Read(Option<io::Result<usize>>),
+    Terminate(FtlError),
+}
+
+/// What the server knows about a session, passed to the lifecycle hooks.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub peer_addr: SocketAddr,
+    /// Set once the client has authenticated.
+    pub channel_id: Option<String>,
+    /// Vendor and codecs, set once the handshake is finalised.
+    pub handshake: Option<FtlHandshakeFinalised>,
+    pub connected_at: SystemTime,
+    pub stream_started_at: Option<SystemTime>,
+    /// Bytes received on the control connection.
+    pub bytes_received: u64,
+    pub pings: u64,
+}
+
+pub struct IngestClient {
+    registration: Registration,
+    handle: IngestHandle,
+    info: SessionInfo,
+    session: FtlSession,
+    activity: MediaActivity,
+}
+
+impl IngestClient {
+    /// Whether the stream should be kept for a `RESUME` if the control connection drops.
+    fn can_roam(&self) -> bool {
+        self.session.is_streaming()
+            && !self.handle.is_shutting_down()
+            && self.info.handshake.as_ref().is_some_and(|handshake| handshake.features.contains(&FtlFeature::Roaming))
+    }
 }
 
 #[async_trait]
 pub trait IngestServer {
     async fn launch(&'static self, addr: String) -> Result<(), io::Error> {
+        self.launch_with_handle(addr, IngestHandle::new()).await
+    }
+
+    /// Launch the server, returning once [`IngestHandle::shutdown`] is called.
+    async fn launch_with_handle(&'static self, addr: String, handle: IngestHandle) -> Result<(), io::Error> {
         let listener = TcpListener::bind(addr).await?;
 
-        while let Ok((stream, address)) = listener.accept().await {
-            info!("Remote client connected: {}", address);
+        loop {
+            let accepted = rt::race(
+                async { listener.accept().await.ok() },
+                async {
+                    handle.closed.recv().await.ok();
+                    None
+                },
+            ).await;
+
+            let (stream, address) = match accepted {
+                Some(accepted) if !handle.is_shutting_down() => accepted,
+                _ => break,
+            };
+
+            let handle = handle.clone();
+            rt::spawn(async move {
+                let mut stream = stream;
+                let config = self.config();
+
+                let mut leftover = Vec::new();
+                let address = if config.proxy_protocol {
+                    match rt::timeout(config.handshake_timeout, read_proxy_header(&mut stream)).await {
+                        Some(Ok((header, rest))) => {
+                            leftover = rest;
+                            match header.source() {
+                                Some(source) => {
+                                    info!("Remote client connected: {} (through proxy {})", source, address);
+                                    source
+                                }
+                                None => {
+                                    info!("Remote client connected: {}", address);
+                                    address
+                                }
+                            }
+                        }
+                        Some(Err(error)) => {
+                            error!("Invalid PROXY protocol header from {}. {}", address, error);
+                            rt::shutdown(&mut stream).await;
+                            return;
+                        }
+                        None => {
+                            error!("No PROXY protocol header received from {}.", address);
+                            rt::shutdown(&mut stream).await;
+                            return;
+                        }
+                    }
+                } else {
+                    info!("Remote client connected: {}", address);
+                    address
+                };
 
-            task::spawn(async move {
-                let (reader, writer) = &mut (&stream, &stream);
+                if let Some(limits) = &config.rate_limits {
+                    if !handle.limiter().allow_connection(address.ip(), limits, Instant::now()) {
+                        debug!("Dropped connection from banned address {}.", address);
+                        rt::shutdown(&mut stream).await;
+                        return;
+                    }
+                }
 
                 // Common data needed by client / server.
-                let (sender, receiver) = bounded(1);
                 let mut client = IngestClient {
-                    channel_id: None,
-                    hmac_payload: util::generate_hmac(),
-                    handshake: FtlHandshake::default(),
-                    stop_signal: receiver,
+                    registration: handle.register(),
+                    handle: handle.clone(),
+                    info: SessionInfo {
+                        peer_addr: address,
+                        channel_id: None,
+                        handshake: None,
+                        connected_at: SystemTime::now(),
+                        stream_started_at: None,
+                        bytes_received: 0,
+                        pings: 0,
+                    },
+                    session: FtlSession::new()
+                        .with_limits(config.limits.clone())
+                        .with_supported_versions(config.supported_versions.clone())
+                        .with_supported_features(config.supported_features.clone()),
+                    activity: MediaActivity::new(),
                 };
 
-                // Socket reader
-                let mut reader = reader.bytes();
-                let mut buffer = Vec::with_capacity(128);
-                while let Some(byte) = reader.next().await {
-                    if let Ok(byte) = byte {
-                        match byte {
-                            b'\n' => {
-                                if buffer.len() > 0 {
-                                    if let Ok(payload) = std::str::from_utf8(&buffer) {
-                                        if let Ok(command) = FtlCommand::from_str(payload) {
-                                            if let Err(error) = self.handler(&mut client, writer, command).await {
-                                                if error.is_err() {
-                                                    error!("Failed to execute FTL command. {:?}", error);
-                                                }
-
-                                                writer.write(
-                                                    error
-                                                        .to_string()
-                                                        .as_bytes()
-                                                )
-                                                .await
-                                                .ok();
-
-                                                break;
-                                            }
-                                        } else {
-                                            error!("Failed to deserialise FTL command. {}", payload);
-                                        }
-                                    } else {
-                                        error!("Failed to convert buffer to UTF8 string.");
-                                    }
-
-                                    buffer.clear();
+                self.on_connect(&client.info).await;
+                client.session.handle_input(&leftover);
+
+                let connected_at = Instant::now();
+                let mut last_read = connected_at;
+                let mut chunk = [0_u8; 1024];
+                // Set if the connection dropped and the stream is kept for the client to resume.
+                let mut dropped = None;
+                loop {
+                    while let Some(event) = client.session.poll_event() {
+                        self.handler(&mut client, event).await;
+                    }
+
+                    let output = client.session.take_output();
+                    if !output.is_empty() && stream.write_all(&output).await.is_err() {
+                        error!("Failed to write to client.");
+                        if client.can_roam() {
+                            dropped = Some(None);
+                            break;
+                        }
+
+                        client.session.close();
+                        continue;
+                    }
+
+                    if client.session.is_closed() {
+                        break;
+                    }
+
+                    let (wait, timeout_error) = if client.session.is_streaming() {
+                        let idle = config.idle_timeout.saturating_sub(last_read.elapsed());
+
+                        match config.media_timeout {
+                            Some(media_timeout) => {
+                                let media = media_timeout.saturating_sub(client.activity.elapsed());
+                                if media < idle {
+                                    (media, FtlError::NoMediaTimeout)
+                                } else {
+                                    (idle, FtlError::InternalSocketTimeout)
                                 }
                             }
-                            // Ignore carriage returns in our implementation.
-                            b'\r' => continue,
-                            byte => buffer.push(byte)
+                            None => (idle, FtlError::InternalSocketTimeout),
                         }
                     } else {
-                        error!("Failed to read anymore bytes from client.");
-                        break;
+                        (config.handshake_timeout.saturating_sub(connected_at.elapsed()), FtlError::InternalSocketTimeout)
+                    };
+
+                    if wait.is_zero() {
+                        if matches!(timeout_error, FtlError::InternalSocketTimeout) && client.can_roam() {
+                            dropped = Some(Some(timeout_error));
+                            break;
+                        }
+
+                        client.session.reject(timeout_error);
+                        continue;
+                    }
+
+                    let terminated = client.registration.terminated.clone();
+
+                    let wake = rt::race(
+                        async { Wake::Read(rt::timeout(wait, stream.read(&mut chunk)).await) },
+                        async {
+                            match terminated.recv().await {
+                                Ok(error) => Wake::Terminate(error),
+                                Err(_) => std::future::pending().await,
+                            }
+                        },
+                    ).await;
+
+                    match wake {
+                        Wake::Read(Some(Ok(0))) if client.can_roam() => {
+                            dropped = Some(None);
+                            break;
+                        }
+                        Wake::Read(Some(Ok(0))) => client.session.close(),
+                        Wake::Read(Some(Ok(read))) => {
+                            last_read = Instant::now();
+                            client.info.bytes_received += read as u64;
+                            client.session.handle_input(&chunk[..read]);
+                        }
+                        Wake::Read(Some(Err(_))) => {
+                            error!("Failed to read anymore bytes from client.");
+                            if client.can_roam() {
+                                dropped = Some(None);
+                                break;
+                            }
+
+                            client.session.close();
+                        }
+                        // Check again, media may have arrived while we were waiting.
+                        Wake::Read(None) => {}
+                        Wake::Terminate(error) => {
+                            info!("Terminating FTL session. {}", error);
+                            client.session.reject(error);
+                        }
                     }
                 }
 
-                info!("Remote FTL client disconnected.");
-                sender.send(()).await.ok();
-                stream.shutdown(std::net::Shutdown::Both).ok();
+                rt::shutdown(&mut stream).await;
+
+                let reason = match dropped {
+                    Some(reason) => reason,
+                    None => {
+                        info!("Remote FTL client disconnected: {}", client.info.peer_addr);
+                        handle.unregister(client.registration.id);
+                        return;
+                    }
+                };
+
+                // Keep the stream and its UDP port around for the client to resume from elsewhere.
+                let IngestClient { registration, info, activity, .. } = client;
+                let id = registration.id;
+                let stop_signal = registration.stop_signal.clone();
+                let terminated = registration.terminated.clone();
+                let (resumed, resumed_signal) = bounded(1);
+
+                info!("Lost connection to FTL client {}, waiting {:?} for it to resume.", info.peer_addr, config.resume_timeout);
+                handle.suspend(SuspendedSession { registration, info, activity, resumed });
+
+                let terminate_error = rt::timeout(config.resume_timeout, rt::race(
+                    async {
+                        resumed_signal.recv().await.ok();
+                        None
+                    },
+                    rt::race(
+                        async { terminated.recv().await.ok() },
+                        async {
+                            stop_signal.recv().await.ok();
+                            None
+                        },
+                    ),
+                )).await.flatten();
+
+                match handle.expire(id) {
+                    Some(SuspendedSession { registration, info, .. }) => {
+                        info!("Remote FTL client did not resume: {}", info.peer_addr);
+                        let reason = terminate_error.or(reason);
+                        self.on_disconnect(&info, reason.as_ref()).await;
+                        handle.unregister(registration.id);
+                    }
+                    None => {
+                        if let Some(error) = terminate_error {
+                            handle.forward_terminate(id, error);
+                        }
+                    }
+                }
             });
         }
 
+        info!("No longer accepting FTL connections.");
         Ok(())
     }
 
-    async fn handler(&self, client: &mut IngestClient, mut writer: &TcpStream, command: FtlCommand) -> Result<(), FtlError> {
-        match command {
-            FtlCommand::HMAC => {
-                debug!("Client requested HMAC payload, sending response.");
-                writer.write(
-                    FtlResponse::HMAC {
-                        hmac_payload: client.hmac_payload.clone()
-                    }
-                    .to_string()
-                    .as_bytes()
-                )
-                .await
-                .map_err(|_| FtlError::IoError)?;
-    
-                Ok(())
-            }
-            FtlCommand::Connect { channel_id, hashed_hmac_payload } => {
+    async fn handler(&self, client: &mut IngestClient, event: FtlSessionEvent) {
+        match event {
+            FtlSessionEvent::AuthenticationRequested { channel_id } => {
                 debug!("Client is connecting, attempting to stream to {}.", &channel_id);
-                let known_key = self.get_stream_key(&channel_id)
-                    .await.map_err(|_| FtlError::InvalidStreamKey)?;
-    
-                // * Key starts with $, omit and decode.
-                let client_hash = hex::decode(hashed_hmac_payload[1..].to_string())
-                    .map_err(|_| FtlError::DecodeError)?;
-                
-                let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA512, &known_key.as_bytes());
-    
-                ring::hmac::verify(
-                    &key,
-                    &hex::decode(client.hmac_payload.clone().into_bytes())
-                        .map_err(|_| FtlError::DecodeError)?,
-                    &client_hash.as_slice()
-                ).map_err(|_| FtlError::RingError)?;
+                match self.get_stream_keys(&channel_id).await {
+                    Ok(keys) => {
+                        if let Some(index) = client.session.authenticate_keys(&keys) {
+                            match &keys[index].label {
+                                Some(label) => info!("Client for {} authenticated with stream key {}.", &channel_id, label),
+                                None => info!("Client for {} authenticated with stream key #{}.", &channel_id, index),
+                            }
+                        }
+                    }
+                    Err(_) => client.session.reject(FtlError::InvalidStreamKey),
+                }
 
+                if client.session.is_closed() {
+                    if let Some(limits) = &self.config().rate_limits {
+                        client.handle.limiter().record_failure(client.info.peer_addr.ip(), limits, Instant::now());
+                    }
+
+                    self.on_auth_failed(&client.info, &channel_id).await;
+                }
+            }
+            FtlSessionEvent::Authenticated { channel_id } => {
                 debug!("Client was verified, ready to stream to {}.", &channel_id);
-                client.channel_id = Some(channel_id);
+                client.handle.set_channel(client.registration.id, &channel_id);
+                client.info.channel_id = Some(channel_id);
+            }
+            FtlSessionEvent::HandshakeFinalised { channel_id, handshake } => {
+                client.info.handshake = Some(handshake.clone());
+                self.on_handshake(&client.info).await;
 
-                writer.write(
-                    FtlResponse::Success
-                        .to_string()
-                        .as_bytes()
-                )
-                .await
-                .map_err(|_| FtlError::IoError)?;
+                if let Err(error) = self.authorize(&channel_id, client.info.peer_addr, &handshake).await {
+                    info!("Refused stream to {} from {}. {}", &channel_id, client.info.peer_addr, error);
+                    client.session.reject(error);
+                    return;
+                }
 
-                Ok(())
-            }
-            FtlCommand::Attribute { key, value } => client.handshake.insert(key, value),
-            FtlCommand::Dot => {
-                if let Some(channel_id) = &client.channel_id {
-                    let handshake = client.handshake.clone().finalise()?;
-                    let udp_port = self.allocate_ingest(channel_id, handshake, client.stop_signal.clone())
-                        .await.map_err(|_| FtlError::AllocateError)?;
-                    
-                    debug!("Client is about to begin stream. Allocated port {}.", udp_port);
-                    writer.write(
-                        FtlResponse::Connect { udp_port }
-                            .to_string()
-                            .as_bytes()
-                    )
-                    .await
-                    .map_err(|_| FtlError::IoError)?;
-
-                    Ok(())
-                } else {
-                    Err(FtlError::InvalidStreamKey)
+                while let Some((terminate, done)) = client.handle.claim(client.registration.id, &channel_id) {
+                    match self.config().channel_policy {
+                        ChannelPolicy::Reject => {
+                            info!("Refused stream to {}, channel is already streaming.", &channel_id);
+                            client.session.reject(FtlError::ChannelInUse);
+                            return;
+                        }
+                        ChannelPolicy::TakeOver => {
+                            info!("Taking over existing stream to {}.", &channel_id);
+                            terminate.try_send(FtlError::ServerTerminate).ok();
+                            done.recv().await.ok();
+                        }
+                    }
                 }
+
+                let video_ssrc = handshake.video.as_ref().map(|video| video.ssrc);
+                let audio_ssrc = handshake.audio.as_ref().map(|audio| audio.ssrc);
+
+                let shared_port = self.config().shared_media_port;
+                if shared_port {
+                    if let Err(error) = client.handle.claim_ssrcs(client.registration.id, None, video_ssrc, audio_ssrc) {
+                        info!("Refused stream to {}. {}", &channel_id, error);
+                        client.session.reject(error);
+                        return;
+                    }
+                }
+
+                let packet_key = client.session.packet_key().cloned();
+                match self.allocate_ingest(&channel_id, client.info.peer_addr, handshake, client.registration.stop_signal.clone(), client.activity.clone(), packet_key).await {
+                    Ok(udp_port) => {
+                        // Ports may still be shared by the ingest, which then tells streams apart by SSRC.
+                        if !shared_port {
+                            if let Err(error) = client.handle.claim_ssrcs(client.registration.id, Some(udp_port), video_ssrc, audio_ssrc) {
+                                info!("Refused stream to {} on port {}. {}", &channel_id, udp_port, error);
+                                client.session.reject(error);
+                                return;
+                            }
+                        }
+
+                        debug!("Client is about to begin stream. Allocated port {}.", udp_port);
+                        client.activity.touch();
+                        client.info.stream_started_at = Some(SystemTime::now());
+                        client.session.start_stream(udp_port);
+                    }
+                    Err(_) => client.session.reject(FtlError::AllocateError),
+                }
+            }
+            FtlSessionEvent::ResumeRequested { channel_id } => {
+                let suspended = match client.handle.resume(&channel_id) {
+                    Some(suspended) => suspended,
+                    None => {
+                        info!("Refused to resume stream to {}, it has already ended.", &channel_id);
+                        client.session.reject(FtlError::ServerTerminate);
+                        return;
+                    }
+                };
+
+                // Carry on as the suspended session, this connection's own registration is no longer needed.
+                let orphan = mem::replace(&mut client.registration, suspended.registration);
+                client.handle.unregister(orphan.id);
+
+                let previous_addr = suspended.info.peer_addr;
+                client.info = SessionInfo {
+                    peer_addr: client.info.peer_addr,
+                    bytes_received: suspended.info.bytes_received + client.info.bytes_received,
+                    ..suspended.info
+                };
+                client.activity = suspended.activity;
+                suspended.resumed.close();
+
+                info!("Resumed stream to {}, client moved from {} to {}.", &channel_id, previous_addr, client.info.peer_addr);
+                client.session.resume_stream();
+                self.on_resume(&client.info, previous_addr).await;
             }
-            FtlCommand::Ping { channel_id } => {
+            FtlSessionEvent::Ping { channel_id } => {
                 trace!("Client sent ping. {}", &channel_id);
-                writer.write(
-                    FtlResponse::Pong
-                        .to_string()
-                        .as_bytes()
-                )
-                .await
-                .map_err(|_| FtlError::IoError)?;
-
-                Ok(())
+                client.info.pings += 1;
+                self.on_ping(&client.info).await;
+            }
+            FtlSessionEvent::Disconnected { error } => {
+                if let Some(error) = &error {
+                    error!("Failed to execute FTL command. {}", error);
+                }
+
+                self.on_disconnect(&client.info, error.as_ref()).await;
             }
-            FtlCommand::Disconnect => Err(FtlError::Disconnect)
         }
     }
 
-    async fn get_stream_key(&self, channel_id: &str) -> Result<String, ()>;
-    async fn allocate_ingest(&self, channel_id: &str, handshake: FtlHandshakeFinalised, stop_signal: Receiver<()>) -> Result<u16, ()>;
+    /// Settings used for new connections.
+    fn config(&self) -> IngestConfig {
+        IngestConfig::default()
+    }
+
+    /// Called when a client connects, before anything has been read.
+    async fn on_connect(&self, _info: &SessionInfo) {}
+
+    /// Called when a client fails to authenticate for `channel_id`.
+    async fn on_auth_failed(&self, _info: &SessionInfo, _channel_id: &str) {}
+
+    /// Called when a client finishes the handshake, before it is authorized.
+    async fn on_handshake(&self, _info: &SessionInfo) {}
+
+    /// Called when a roaming client picks its stream back up from a new connection.
+    ///
+    /// `info.peer_addr` is the new address, media will now come from there rather than `previous_addr`.
+    /// Ingests which only accept media from the client's address must be updated here, the default does nothing.
+    async fn on_resume(&self, _info: &SessionInfo, _previous_addr: SocketAddr) {}
+
+    /// Called for every `PING` received.
+    async fn on_ping(&self, _info: &SessionInfo) {}
+
+    /// Called once a session is over, `reason` is `None` if the client disconnected cleanly.
+    async fn on_disconnect(&self, _info: &SessionInfo, _reason: Option<&FtlError>) {}
+
+    /// Look up the stream key for a channel, implement this or [`IngestServer::get_stream_keys`].
+    async fn get_stream_key(&self, _channel_id: &str) -> Result<String, ()> {
+        Err(())
+    }
+
+    /// Look up every key currently accepted for a channel, the first unexpired key
+    /// matching the client's HMAC is used. Defaults to [`IngestServer::get_stream_key`].
+    ///
+    /// Give keys an expiry to rotate them with an overlap, or separate keys (and labels) to co-hosts.
+    async fn get_stream_keys(&self, channel_id: &str) -> Result<Vec<StreamKey>, ()> {
+        self.get_stream_key(channel_id).await.map(|key| vec![StreamKey::new(&key)])
+    }
+
+    /// Decide whether an authenticated client may stream, called before [`IngestServer::allocate_ingest`].
+    ///
+    /// The handshake carries the vendor attributes, including any this crate doesn't know about.
+    /// Reject with [`FtlError::ChannelNotAuthorized`], [`FtlError::UnsupportedRegion`],
+    /// [`FtlError::GameBlocked`] or [`FtlError::ChannelInUse`] to tell the client why.
+    async fn authorize(&self, _channel_id: &str, _peer_addr: SocketAddr, _handshake: &FtlHandshakeFinalised) -> Result<(), FtlError> {
+        Ok(())
+    }
+
+    /// Allocate a UDP port for the client's media.
+    ///
+    /// `peer_addr` is the address of the control connection, media should only be
+    /// accepted from the same host. `stop_signal` is closed once the stream ends,
+    /// at which point the ingest should be torn down. Call [`MediaActivity::touch`] on `activity`
+    /// whenever media is received if [`IngestConfig::media_timeout`] is set.
+    ///
+    /// `packet_key` is set if the client agreed to [`FtlFeature::SignedPackets`],
+    /// RTP packets without a valid signature should then be dropped, see [`PacketKey::verify`].
+    async fn allocate_ingest(&self, channel_id: &str, peer_addr: SocketAddr, handshake: FtlHandshakeFinalised, stop_signal: Receiver<()>, activity: MediaActivity, packet_key: Option<PacketKey>) -> Result<u16, ()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use async_channel::Receiver;
+    use async_trait::async_trait;
+
+    use crate::protocol::{FtlError, FtlFeature, FtlHandshakeFinalised};
+    use crate::rt::{self, AsyncReadExt, AsyncWriteExt, JoinHandle, TcpListener, TcpStream};
+    use crate::signing::PacketKey;
+    use crate::util;
+    use crate::server::{ChannelPolicy, IngestConfig, IngestHandle, IngestServer, MediaActivity, RateLimiter, RateLimits, RateLimitStats, SessionInfo};
+
+    const STREAM_KEY: &str = "ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ";
+
+    struct TestServer {
+        config: fn() -> IngestConfig,
+        log: Mutex<Vec<String>>,
+    }
+
+    fn config() -> IngestConfig {
+        IngestConfig {
+            handshake_timeout: Duration::from_secs(60),
+            ..IngestConfig::default()
+        }
+    }
+
+    static SERVER: TestServer = TestServer {
+        config,
+        log: Mutex::new(Vec::new()),
+    };
+
+    #[async_trait]
+    impl IngestServer for TestServer {
+        fn config(&self) -> IngestConfig {
+            (self.config)()
+        }
+
+        async fn on_connect(&self, info: &SessionInfo) {
+            self.log.lock().unwrap().push(format!("connect {}", info.peer_addr.ip()));
+        }
+
+        async fn on_auth_failed(&self, _info: &SessionInfo, channel_id: &str) {
+            self.log.lock().unwrap().push(format!("auth failed {}", channel_id));
+        }
+
+        async fn on_handshake(&self, info: &SessionInfo) {
+            let vendor = info.handshake.as_ref().and_then(|handshake| handshake.vendor.name.clone());
+            self.log.lock().unwrap().push(format!("handshake {:?}", vendor));
+        }
+
+        async fn on_resume(&self, info: &SessionInfo, previous_addr: SocketAddr) {
+            self.log.lock().unwrap().push(format!("resume {:?} {} -> {}", info.channel_id, previous_addr.ip(), info.peer_addr.ip()));
+        }
+
+        async fn on_ping(&self, info: &SessionInfo) {
+            self.log.lock().unwrap().push(format!("ping {}", info.pings));
+        }
+
+        async fn on_disconnect(&self, info: &SessionInfo, reason: Option<&FtlError>) {
+            let reason = reason.map(|error| error.to_string());
+            self.log.lock().unwrap().push(format!("disconnect {:?} {:?}", info.channel_id, reason));
+        }
+
+        async fn get_stream_key(&self, _channel_id: &str) -> Result<String, ()> {
+            Ok(STREAM_KEY.to_string())
+        }
+
+        async fn authorize(&self, channel_id: &str, _peer_addr: SocketAddr, handshake: &FtlHandshakeFinalised) -> Result<(), FtlError> {
+            match handshake.vendor.name.as_deref() {
+                Some("blocked") if channel_id == "77" => Err(FtlError::GameBlocked),
+                _ => Ok(()),
+            }
+        }
+
+        async fn allocate_ingest(&self, _channel_id: &str, _peer_addr: SocketAddr, handshake: FtlHandshakeFinalised, _stop_signal: Receiver<()>, _activity: MediaActivity, _packet_key: Option<PacketKey>) -> Result<u16, ()> {
+            match handshake.vendor.name.as_deref() {
+                Some("broken") => Err(()),
+                _ => Ok(24000),
+            }
+        }
+    }
+
+    async fn launch(server: &'static TestServer, handle: IngestHandle) -> (SocketAddr, JoinHandle<io::Result<()>>) {
+        // Find a free port for the server.
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let server = rt::spawn(server.launch_with_handle(addr.to_string(), handle));
+        rt::sleep(Duration::from_millis(50)).await;
+        (addr, server)
+    }
+
+    async fn connect(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"HMAC\r\n\r\n").await.unwrap();
+        stream
+    }
+
+    async fn read_line(stream: &mut TcpStream) -> String {
+        let mut line = vec![];
+        let mut byte = [0_u8];
+        while stream.read(&mut byte).await.unwrap() == 1 && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+
+        String::from_utf8(line).unwrap()
+    }
+
+    async fn authenticate(stream: &mut TcpStream, channel_id: &str) {
+        let line = read_line(stream).await;
+        let hashed = util::hash_hmac(line.trim_start_matches("200 "), STREAM_KEY).unwrap();
+        stream.write_all(format!("CONNECT {} ${}\r\n\r\n", channel_id, hashed).as_bytes()).await.unwrap();
+        assert_eq!(read_line(stream).await, "200");
+    }
+
+    async fn resume(stream: &mut TcpStream, channel_id: &str) -> String {
+        let line = read_line(stream).await;
+        let hashed = util::hash_hmac(line.trim_start_matches("200 "), STREAM_KEY).unwrap();
+        stream.write_all(format!("RESUME {} ${}\r\n\r\n", channel_id, hashed).as_bytes()).await.unwrap();
+        read_line(stream).await
+    }
+
+    async fn start_stream(stream: &mut TcpStream, attributes: &[&str]) -> String {
+        let mut handshake = String::from("ProtocolVersion: 0.9\r\n\r\n");
+        for attribute in attributes {
+            handshake += attribute;
+            handshake += "\r\n\r\n";
+        }
+
+        handshake += ".\r\n\r\n";
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        read_line(stream).await
+    }
+
+    async fn read_to_end(stream: &mut TcpStream) -> String {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[rt::test]
+    async fn should_time_out_handshake() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                handshake_timeout: Duration::from_millis(100),
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+        let mut stream = connect(addr).await;
+
+        let response = read_to_end(&mut stream).await;
+        assert!(response.ends_with("903 Internal Socket Timeout\n"), "{:?}", response);
+    }
+
+    #[rt::test]
+    async fn should_time_out_idle_stream() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                idle_timeout: Duration::from_millis(150),
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+        assert_eq!(start_stream(&mut stream, &[]).await, "200. Use UDP port 24000");
+
+        // Pings keep the stream alive, until they stop.
+        rt::sleep(Duration::from_millis(100)).await;
+        stream.write_all(b"PING 77\r\n\r\n").await.unwrap();
+        assert_eq!(read_line(&mut stream).await, "201");
+        assert_eq!(read_to_end(&mut stream).await, "903 Internal Socket Timeout\n");
+    }
+
+    #[rt::test]
+    async fn should_time_out_without_media() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                media_timeout: Some(Duration::from_millis(100)),
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+        assert_eq!(start_stream(&mut stream, &[]).await, "200. Use UDP port 24000");
+
+        // Our test server never touches the activity, so pings alone don't help.
+        stream.write_all(b"PING 77\r\n\r\n").await.unwrap();
+        assert_eq!(read_line(&mut stream).await, "201");
+        assert_eq!(read_to_end(&mut stream).await, "408 No media received\n");
+    }
+
+    #[rt::test]
+    async fn should_explain_refusal() {
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+
+        for (vendor, expected) in [
+            ("VendorName: blocked", "409 Channel is not allowed to stream set game"),
+            ("VendorName: broken", "500 Internal Server Error"),
+        ] {
+            let mut stream = connect(addr).await;
+            authenticate(&mut stream, "77").await;
+            assert_eq!(start_stream(&mut stream, &[vendor]).await, expected);
+        }
+    }
+
+    #[rt::test]
+    async fn should_terminate_channel() {
+        let handle = IngestHandle::new();
+        let (addr, _) = launch(&SERVER, handle.clone()).await;
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+
+        assert_eq!(handle.channels(), vec!["77".to_string()]);
+        assert!(!handle.terminate("78", FtlError::GameBlocked));
+        assert!(handle.terminate("77", FtlError::GameBlocked));
+        assert_eq!(read_to_end(&mut stream).await, "409 Channel is not allowed to stream set game\n");
+    }
+
+    #[rt::test]
+    async fn should_allow_one_stream_per_channel() {
+        let handle = IngestHandle::new();
+        let (addr, _) = launch(&SERVER, handle.clone()).await;
+
+        let mut first = connect(addr).await;
+        authenticate(&mut first, "77").await;
+        assert_eq!(start_stream(&mut first, &[]).await, "200. Use UDP port 24000");
+        assert!(handle.is_streaming("77"));
+
+        let mut second = connect(addr).await;
+        authenticate(&mut second, "77").await;
+        assert_eq!(start_stream(&mut second, &[]).await, "406 Channel actively streaming");
+    }
+
+    #[rt::test]
+    async fn should_detect_ssrc_collisions() {
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+        let audio = ["Audio: true", "AudioCodec: OPUS", "AudioPayloadType: 97", "AudioIngestSSRC: 5"];
+
+        let mut first = connect(addr).await;
+        authenticate(&mut first, "77").await;
+        assert_eq!(start_stream(&mut first, &audio).await, "200. Use UDP port 24000");
+
+        // Our test server puts every stream on the same port.
+        let mut second = connect(addr).await;
+        authenticate(&mut second, "78").await;
+        assert_eq!(start_stream(&mut second, &audio).await, "403 Audio SSRC collision");
+    }
+
+    #[rt::test]
+    async fn should_check_shared_port_ssrcs_before_allocating() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                shared_media_port: true,
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+        let audio = ["Audio: true", "AudioCodec: OPUS", "AudioPayloadType: 97", "AudioIngestSSRC: 5"];
+
+        let mut first = connect(addr).await;
+        authenticate(&mut first, "77").await;
+        assert_eq!(start_stream(&mut first, &audio).await, "200. Use UDP port 24000");
+
+        // Allocating would fail for this vendor, so the collision must be found first.
+        let mut second = connect(addr).await;
+        authenticate(&mut second, "78").await;
+        assert_eq!(start_stream(&mut second, &[&audio[..], &["VendorName: broken"]].concat()).await, "403 Audio SSRC collision");
+    }
+
+    #[rt::test]
+    async fn should_take_over_channel() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                channel_policy: ChannelPolicy::TakeOver,
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+
+        let mut first = connect(addr).await;
+        authenticate(&mut first, "77").await;
+        assert_eq!(start_stream(&mut first, &[]).await, "200. Use UDP port 24000");
+
+        let mut second = connect(addr).await;
+        authenticate(&mut second, "77").await;
+        assert_eq!(start_stream(&mut second, &[]).await, "200. Use UDP port 24000");
+        assert_eq!(read_to_end(&mut first).await, "410 Server terminated stream\n");
+    }
+
+    #[rt::test]
+    async fn should_call_lifecycle_hooks() {
+        static SERVER: TestServer = TestServer {
+            config,
+            log: Mutex::new(Vec::new()),
+        };
+
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+
+        let mut stream = connect(addr).await;
+        read_line(&mut stream).await;
+        stream.write_all(b"CONNECT 78 $00\r\n\r\n").await.unwrap();
+        read_to_end(&mut stream).await;
+
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+        start_stream(&mut stream, &["VendorName: obs"]).await;
+        stream.write_all(b"PING 77\r\n\r\nDISCONNECT\r\n\r\n").await.unwrap();
+        read_to_end(&mut stream).await;
+
+        assert_eq!(*SERVER.log.lock().unwrap(), vec![
+            "connect 127.0.0.1",
+            "auth failed 78",
+            "disconnect None Some(\"HMAC verification failed\")",
+            "connect 127.0.0.1",
+            "handshake Some(\"obs\")",
+            "ping 1",
+            "disconnect Some(\"77\") None",
+        ]);
+    }
+
+    #[rt::test]
+    async fn should_resume_stream() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                supported_features: vec![FtlFeature::Roaming].into_iter().collect(),
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let handle = IngestHandle::new();
+        let (addr, _) = launch(&SERVER, handle.clone()).await;
+
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+        assert_eq!(start_stream(&mut stream, &["Features: Roaming"]).await, "200. Use UDP port 24000. Features: Roaming");
+        drop(stream);
+
+        rt::sleep(Duration::from_millis(50)).await;
+        assert!(handle.is_streaming("77"));
+
+        let mut stream = connect(addr).await;
+        assert_eq!(resume(&mut stream, "77").await, "200");
+        stream.write_all(b"PING 77\r\n\r\n").await.unwrap();
+        assert_eq!(read_line(&mut stream).await, "201");
+        stream.write_all(b"DISCONNECT\r\n\r\n").await.unwrap();
+        read_to_end(&mut stream).await;
+
+        // Nothing left to resume once the stream is over.
+        let mut stream = connect(addr).await;
+        assert_eq!(resume(&mut stream, "77").await, "410 Server terminated stream");
+
+        assert_eq!(*SERVER.log.lock().unwrap(), vec![
+            "connect 127.0.0.1",
+            "handshake None",
+            "connect 127.0.0.1",
+            "resume Some(\"77\") 127.0.0.1 -> 127.0.0.1",
+            "ping 1",
+            "disconnect Some(\"77\") None",
+            "connect 127.0.0.1",
+            "disconnect None Some(\"server terminated the stream\")",
+        ]);
+    }
+
+    #[rt::test]
+    async fn should_resume_from_new_address() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                supported_features: vec![FtlFeature::Roaming].into_iter().collect(),
+                proxy_protocol: true,
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let handle = IngestHandle::new();
+        let (addr, _) = launch(&SERVER, handle.clone()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 8084\r\nHMAC\r\n\r\n").await.unwrap();
+        authenticate(&mut stream, "77").await;
+        start_stream(&mut stream, &["Features: Roaming"]).await;
+        drop(stream);
+
+        // The client switched networks.
+        rt::sleep(Duration::from_millis(50)).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"PROXY TCP4 198.51.100.2 10.0.0.1 41234 8084\r\nHMAC\r\n\r\n").await.unwrap();
+        assert_eq!(resume(&mut stream, "77").await, "200");
+
+        assert_eq!(SERVER.log.lock().unwrap().last().unwrap(), "resume Some(\"77\") 203.0.113.7 -> 198.51.100.2");
+    }
+
+    #[rt::test]
+    async fn should_end_stream_without_resume() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                supported_features: vec![FtlFeature::Roaming].into_iter().collect(),
+                resume_timeout: Duration::from_millis(100),
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let handle = IngestHandle::new();
+        let (addr, _) = launch(&SERVER, handle.clone()).await;
+
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+        start_stream(&mut stream, &["Features: Roaming"]).await;
+        drop(stream);
+
+        rt::sleep(Duration::from_millis(300)).await;
+        assert!(!handle.is_streaming("77"));
+        assert_eq!(SERVER.log.lock().unwrap().last().unwrap(), "disconnect Some(\"77\") None");
+    }
+
+    #[rt::test]
+    async fn should_use_proxied_address() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                proxy_protocol: true,
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let (addr, _) = launch(&SERVER, IngestHandle::new()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 8084\r\nHMAC\r\n\r\n").await.unwrap();
+        authenticate(&mut stream, "77").await;
+
+        // Connections without a header are dropped without a response.
+        let mut stream = connect(addr).await;
+        assert_eq!(read_to_end(&mut stream).await, "");
+
+        assert_eq!(*SERVER.log.lock().unwrap(), vec!["connect 203.0.113.7"]);
+    }
+
+    #[test]
+    fn should_back_off_bans() {
+        let limits = RateLimits {
+            max_connections: 2,
+            max_failed_attempts: 1,
+            ..RateLimits::default()
+        };
+
+        let ip = "203.0.113.7".parse().unwrap();
+        let start = Instant::now();
+        let mut limiter = RateLimiter::default();
+
+        assert!(limiter.allow_connection(ip, &limits, start));
+        assert!(limiter.allow_connection(ip, &limits, start));
+        assert!(!limiter.allow_connection(ip, &limits, start));
+
+        // First ban lasts 30 seconds, the second twice as long.
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.allow_connection(ip, &limits, later));
+        limiter.record_failure(ip, &limits, later);
+        assert!(!limiter.allow_connection(ip, &limits, later + Duration::from_secs(59)));
+        assert!(limiter.allow_connection(ip, &limits, later + Duration::from_secs(60)));
+
+        // Bans are forgotten once an address has been quiet for long enough.
+        let much_later = later + Duration::from_secs(60 * 60 * 2);
+        limiter.record_failure(ip, &limits, much_later);
+        assert!(limiter.allow_connection(ip, &limits, much_later + Duration::from_secs(30)));
+
+        assert_eq!(limiter.stats, RateLimitStats {
+            refused_connections: 2,
+            failed_attempts: 2,
+            bans: 3,
+            banned_addresses: 0,
+            evicted_addresses: 0,
+        });
+    }
+
+    #[test]
+    fn should_limit_tracked_addresses() {
+        let limits = RateLimits {
+            max_connections: 1,
+            max_ban_duration: Duration::from_secs(60),
+            max_tracked_addresses: 2,
+            ..RateLimits::default()
+        };
+
+        let start = Instant::now();
+        let mut limiter = RateLimiter::default();
+
+        // Addresses in the same /64 share a record.
+        assert!(limiter.allow_connection("2001:db8::1".parse().unwrap(), &limits, start));
+        assert!(!limiter.allow_connection("2001:db8::2".parse().unwrap(), &limits, start));
+        assert!(limiter.allow_connection("2001:db8:0:1::1".parse().unwrap(), &limits, start));
+
+        // A new address takes the place of the oldest one which isn't banned.
+        let ip = "203.0.113.7".parse().unwrap();
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.allow_connection(ip, &limits, later));
+        assert!(limiter.addresses.contains_key(&ip));
+        assert!(!limiter.addresses.contains_key(&"2001:db8:0:1::".parse().unwrap()));
+
+        // Once every tracked address is banned, new ones are refused.
+        assert!(!limiter.allow_connection(ip, &limits, later));
+        assert!(!limiter.allow_connection("203.0.113.8".parse().unwrap(), &limits, later));
+        assert_eq!(limiter.addresses.len(), 2);
+        assert_eq!(limiter.stats.refused_connections, 3);
+        assert_eq!(limiter.stats.evicted_addresses, 1);
+    }
+
+    #[rt::test]
+    async fn should_ban_failed_attempts() {
+        static SERVER: TestServer = TestServer {
+            config: || IngestConfig {
+                rate_limits: Some(RateLimits {
+                    max_failed_attempts: 2,
+                    ban_duration: Duration::from_millis(200),
+                    ..RateLimits::default()
+                }),
+                ..config()
+            },
+            log: Mutex::new(Vec::new()),
+        };
+
+        let handle = IngestHandle::new();
+        let (addr, _) = launch(&SERVER, handle.clone()).await;
+
+        for _ in 0..2 {
+            let mut stream = connect(addr).await;
+            read_line(&mut stream).await;
+            stream.write_all(b"CONNECT 77 $00\r\n\r\n").await.unwrap();
+            read_to_end(&mut stream).await;
+        }
+
+        // Banned addresses don't even get an HMAC payload.
+        let mut stream = connect(addr).await;
+        assert_eq!(read_to_end(&mut stream).await, "");
+
+        assert_eq!(handle.rate_limit_stats(), RateLimitStats {
+            refused_connections: 1,
+            failed_attempts: 2,
+            bans: 1,
+            banned_addresses: 1,
+            evicted_addresses: 0,
+        });
+
+        rt::sleep(Duration::from_millis(250)).await;
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+    }
+
+    #[rt::test]
+    async fn should_terminate_on_shutdown() {
+        let handle = IngestHandle::new();
+        let (addr, server) = launch(&SERVER, handle.clone()).await;
+        let mut stream = connect(addr).await;
+
+        // Wait for the HMAC payload so the server has read everything we sent.
+        read_line(&mut stream).await;
+
+        handle.shutdown(Duration::from_millis(100)).await;
+        rt::join(server).await.unwrap();
+
+        let response = read_to_end(&mut stream).await;
+        assert!(response.ends_with("410 Server terminated stream\n"), "{:?}", response);
+    }
+
+    #[rt::test]
+    async fn should_keep_streaming_until_shutdown_deadline() {
+        let handle = IngestHandle::new();
+        let (addr, server) = launch(&SERVER, handle.clone()).await;
+        let mut stream = connect(addr).await;
+        authenticate(&mut stream, "77").await;
+        assert_eq!(start_stream(&mut stream, &[]).await, "200. Use UDP port 24000");
+
+        let shutdown = {
+            let handle = handle.clone();
+            rt::spawn(async move { handle.shutdown(Duration::from_millis(250)).await })
+        };
+
+        rt::sleep(Duration::from_millis(100)).await;
+        assert!(handle.entries().values().all(|entry| !entry.stop.is_closed()));
+
+        rt::join(shutdown).await;
+        rt::join(server).await.unwrap();
+        assert_eq!(read_to_end(&mut stream).await, "410 Server terminated stream\n");
+    }
 }
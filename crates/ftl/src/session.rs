@@ -0,0 +1,634 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::time::SystemTime;
+
+use log::error;
+
+use crate::protocol::{self, FtlCommand, FtlError, FtlFeature, FtlFeatures, FtlHandshake, FtlHandshakeFinalised, FtlResponse, ProtocolVersion, VersionReq};
+use crate::signing::PacketKey;
+use crate::util;
+
+/// Something the application needs to know about or act upon.
+// Events are short lived, boxing the handshake would only make matching on it awkward.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum FtlSessionEvent {
+    /// Client wants to stream to this channel, or resume a stream to it.
+    ///
+    /// Look up the channel's stream key and pass it to [`FtlSession::authenticate`]
+    /// (or [`FtlSession::authenticate_keys`] if it has several), or refuse
+    /// with [`FtlSession::reject`]. No further input is processed until then.
+    AuthenticationRequested { channel_id: String },
+    /// Client proved it holds the stream key for this channel.
+    Authenticated { channel_id: String },
+    /// Client proved it holds the stream key and wants to pick up a stream whose connection dropped.
+    ///
+    /// Reattach it to the stream and call [`FtlSession::resume_stream`],
+    /// or refuse with [`FtlSession::reject`]. No further input is processed until then.
+    ResumeRequested { channel_id: String },
+    /// Client finished the handshake and wants to start sending media.
+    ///
+    /// Allocate a UDP port and pass it to [`FtlSession::start_stream`],
+    /// or refuse with [`FtlSession::reject`]. No further input is processed until then.
+    HandshakeFinalised {
+        channel_id: String,
+        handshake: FtlHandshakeFinalised,
+    },
+    /// Client sent a keep-alive, the response has already been queued.
+    Ping { channel_id: String },
+    /// Session is over, either because the client disconnected or because of an error.
+    ///
+    /// If there was an error, it has already been queued for the client.
+    Disconnected { error: Option<FtlError> },
+}
+
+/// Limits on what a client may send before it is disconnected.
+#[derive(Debug, Clone)]
+pub struct FtlLimits {
+    /// Longest line accepted from the client, in bytes.
+    pub max_line_length: usize,
+    /// Most attributes accepted during the handshake.
+    pub max_attributes: usize,
+}
+
+impl Default for FtlLimits {
+    fn default() -> Self {
+        FtlLimits {
+            max_line_length: 1024,
+            max_attributes: 64,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Connected,
+    AwaitingKey {
+        channel_id: String,
+        hashed_hmac_payload: String,
+        resume: bool,
+    },
+    Authenticated { channel_id: String },
+    AwaitingResume { channel_id: String },
+    AwaitingPort { channel_id: String },
+    Streaming { channel_id: String },
+    Closed,
+}
+
+/// FTL control protocol state machine which does not perform any IO.
+///
+/// Feed it bytes received from the client with [`FtlSession::handle_input`],
+/// act upon events from [`FtlSession::poll_event`] and send anything returned
+/// by [`FtlSession::take_output`] back to the client.
+pub struct FtlSession {
+    state: State,
+    hmac_payload: String,
+    handshake: FtlHandshake,
+    attributes: usize,
+    limits: FtlLimits,
+    supported_versions: VersionReq,
+    supported_features: FtlFeatures,
+    /// Features agreed with the client, `None` unless it asked for any.
+    features: Option<FtlFeatures>,
+    /// Derived from the stream key the client authenticated with.
+    packet_key: Option<PacketKey>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    events: VecDeque<FtlSessionEvent>,
+}
+
+impl Default for FtlSession {
+    fn default() -> Self {
+        FtlSession::new()
+    }
+}
+
+impl FtlSession {
+    /// Create a new session with a random HMAC payload.
+    pub fn new() -> FtlSession {
+        FtlSession::with_hmac_payload(util::generate_hmac())
+    }
+
+    /// Create a new session with a known hex encoded HMAC payload.
+    pub fn with_hmac_payload(hmac_payload: String) -> FtlSession {
+        FtlSession {
+            state: State::Connected,
+            hmac_payload,
+            handshake: FtlHandshake::default(),
+            attributes: 0,
+            limits: FtlLimits::default(),
+            supported_versions: ProtocolVersion::default_supported(),
+            supported_features: FtlFeatures::default(),
+            features: None,
+            packet_key: None,
+            input: Vec::with_capacity(128),
+            output: Vec::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Replace the default limits.
+    pub fn with_limits(mut self, limits: FtlLimits) -> FtlSession {
+        self.limits = limits;
+        self
+    }
+
+    /// Protocol versions to accept, clients sending any other version are refused
+    /// with `402 Outdated FTL SDK version`.
+    pub fn with_supported_versions(mut self, supported_versions: VersionReq) -> FtlSession {
+        self.supported_versions = supported_versions;
+        self
+    }
+
+    /// Features this server supports, none by default.
+    ///
+    /// Clients are offered those they ask for in their `Features` attribute, the
+    /// result is in the finalised handshake and sent back along with the UDP port.
+    pub fn with_supported_features(mut self, supported_features: FtlFeatures) -> FtlSession {
+        self.supported_features = supported_features;
+        self
+    }
+
+    /// Channel this session is authenticated for, if any.
+    pub fn channel_id(&self) -> Option<&str> {
+        match &self.state {
+            State::Authenticated { channel_id }
+            | State::AwaitingResume { channel_id }
+            | State::AwaitingPort { channel_id }
+            | State::Streaming { channel_id } => Some(channel_id),
+            _ => None,
+        }
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        matches!(self.state, State::Streaming { .. })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// Buffer bytes received from the client.
+    pub fn handle_input(&mut self, data: &[u8]) {
+        if !self.is_closed() {
+            self.input.extend_from_slice(data);
+        }
+    }
+
+    /// Bytes which should be sent to the client.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output)
+    }
+
+    /// Process buffered input until there is something to report.
+    pub fn poll_event(&mut self) -> Option<FtlSessionEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+
+            match self.state {
+                State::AwaitingKey { .. } | State::AwaitingResume { .. } | State::AwaitingPort { .. } | State::Closed => return None,
+                _ => {}
+            }
+
+            let line = match self.next_line() {
+                Ok(line) => line?,
+                Err(error) => {
+                    self.fail(error);
+                    continue;
+                }
+            };
+
+            match FtlCommand::from_bytes(&line) {
+                Ok(command) => {
+                    if let Err(error) = self.handle_command(command) {
+                        self.fail(error);
+                    }
+                }
+                Err(_) => error!("Failed to deserialise FTL command. {}", String::from_utf8_lossy(&line)),
+            }
+        }
+    }
+
+    /// Verify the client's hashed HMAC payload against the channel's stream key.
+    pub fn authenticate(&mut self, stream_key: &str) {
+        self.authenticate_keys(&[StreamKey::new(stream_key)]);
+    }
+
+    /// Verify the client's hashed HMAC payload against each of the channel's unexpired stream keys.
+    ///
+    /// Returns the index of the key which matched, if any.
+    pub fn authenticate_keys(&mut self, keys: &[StreamKey]) -> Option<usize> {
+        let (channel_id, resume, result) = match &self.state {
+            State::AwaitingKey { channel_id, hashed_hmac_payload, resume } => {
+                let now = SystemTime::now();
+                let mut result = Err(FtlError::InvalidStreamKey);
+
+                for (index, key) in keys.iter().enumerate().filter(|(_, key)| !key.is_expired(now)) {
+                    result = verify_hmac(&self.hmac_payload, &key.key, hashed_hmac_payload).map(|_| index);
+                    if !matches!(result, Err(FtlError::RingError)) {
+                        break;
+                    }
+                }
+
+                (channel_id.clone(), *resume, result)
+            }
+            _ => return None,
+        };
+
+        match result {
+            Ok(index) if resume => {
+                self.state = State::AwaitingResume { channel_id: channel_id.clone() };
+                self.events.push_back(FtlSessionEvent::ResumeRequested { channel_id });
+                Some(index)
+            }
+            Ok(index) => {
+                self.packet_key = PacketKey::derive(&self.hmac_payload, &keys[index].key).ok();
+                self.respond(FtlResponse::Success);
+                self.state = State::Authenticated { channel_id: channel_id.clone() };
+                self.events.push_back(FtlSessionEvent::Authenticated { channel_id });
+                Some(index)
+            }
+            Err(error) => {
+                self.fail(error);
+                None
+            }
+        }
+    }
+
+    /// Key the client signs media packets with, once [`FtlFeature::SignedPackets`] has been agreed on.
+    pub fn packet_key(&self) -> Option<&PacketKey> {
+        let signed = self.features.as_ref().is_some_and(|features| features.contains(&FtlFeature::SignedPackets));
+        self.packet_key.as_ref().filter(|_| signed)
+    }
+
+    /// Tell the client where to send media.
+    pub fn start_stream(&mut self, udp_port: u16) {
+        if let State::AwaitingPort { channel_id } = &self.state {
+            self.state = State::Streaming { channel_id: channel_id.clone() };
+            let features = self.features.clone();
+            self.respond(FtlResponse::Connect { udp_port, features });
+        }
+    }
+
+    /// Tell the client its stream was picked back up, it should carry on sending media as before.
+    pub fn resume_stream(&mut self) {
+        if let State::AwaitingResume { channel_id } = &self.state {
+            self.state = State::Streaming { channel_id: channel_id.clone() };
+            self.respond(FtlResponse::Success);
+        }
+    }
+
+    /// Refuse a pending request (or end the session) with the given error.
+    pub fn reject(&mut self, error: FtlError) {
+        if !self.is_closed() {
+            self.fail(error);
+        }
+    }
+
+    /// The underlying transport was closed by the client.
+    pub fn close(&mut self) {
+        if !self.is_closed() {
+            self.state = State::Closed;
+            self.events.push_back(FtlSessionEvent::Disconnected { error: None });
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Option<Vec<u8>>, FtlError> {
+        let (consumed, line) = protocol::next_line(&self.input, self.limits.max_line_length)?;
+        self.input.drain(..consumed);
+        Ok(line)
+    }
+
+    fn handle_command(&mut self, command: FtlCommand) -> Result<(), FtlError> {
+        match command {
+            FtlCommand::HMAC => {
+                self.respond(FtlResponse::HMAC {
+                    hmac_payload: self.hmac_payload.clone(),
+                });
+            }
+            FtlCommand::Connect { channel_id, hashed_hmac_payload } => {
+                if let State::AwaitingPort { .. } | State::Streaming { .. } = self.state {
+                    return Err(FtlError::UnimplementedCommand);
+                }
+
+                self.state = State::AwaitingKey {
+                    channel_id: channel_id.clone(),
+                    hashed_hmac_payload,
+                    resume: false,
+                };
+
+                self.events.push_back(FtlSessionEvent::AuthenticationRequested { channel_id });
+            }
+            FtlCommand::Resume { channel_id, hashed_hmac_payload } => {
+                // Only a fresh connection can resume, and only if roaming is on offer.
+                if !matches!(self.state, State::Connected) || !self.supported_features.contains(&FtlFeature::Roaming) {
+                    return Err(FtlError::UnimplementedCommand);
+                }
+
+                self.state = State::AwaitingKey {
+                    channel_id: channel_id.clone(),
+                    hashed_hmac_payload,
+                    resume: true,
+                };
+
+                self.events.push_back(FtlSessionEvent::AuthenticationRequested { channel_id });
+            }
+            FtlCommand::Attribute { key, value } => {
+                self.attributes += 1;
+                if self.attributes > self.limits.max_attributes {
+                    return Err(FtlError::TooManyAttributes);
+                }
+
+                self.handshake.insert(key, value)?
+            }
+            FtlCommand::Dot => {
+                if let State::Authenticated { channel_id } = &self.state {
+                    let channel_id = channel_id.clone();
+                    let mut handshake = self.handshake.clone().finalise_with(&self.supported_versions)?;
+                    handshake.features = handshake.features.intersection(&self.supported_features);
+
+                    if self.handshake.features.is_some() {
+                        self.features = Some(handshake.features.clone());
+                    }
+
+                    self.state = State::AwaitingPort { channel_id: channel_id.clone() };
+                    self.events.push_back(FtlSessionEvent::HandshakeFinalised { channel_id, handshake });
+                } else {
+                    return Err(FtlError::InvalidStreamKey);
+                }
+            }
+            FtlCommand::Ping { channel_id } => {
+                self.respond(FtlResponse::Pong);
+                self.events.push_back(FtlSessionEvent::Ping { channel_id });
+            }
+            FtlCommand::Disconnect => return Err(FtlError::Disconnect),
+        }
+
+        Ok(())
+    }
+
+    fn respond(&mut self, response: FtlResponse) {
+        self.output.extend_from_slice(response.to_string().as_bytes());
+    }
+
+    fn fail(&mut self, error: FtlError) {
+        if let Some(response) = error.to_response() {
+            self.respond(response);
+        }
+
+        self.state = State::Closed;
+        self.events.push_back(FtlSessionEvent::Disconnected {
+            error: if error.is_err() { Some(error) } else { None },
+        });
+    }
+}
+
+/// A stream key accepted for a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamKey {
+    pub key: String,
+    /// Logged when the key is used, such as who it was given to. Never log the key itself.
+    pub label: Option<String>,
+    /// Key is no longer accepted after this time, leave keys being rotated out valid for a while.
+    pub expires_at: Option<SystemTime>,
+}
+
+impl StreamKey {
+    pub fn new(key: &str) -> StreamKey {
+        StreamKey {
+            key: key.to_string(),
+            label: None,
+            expires_at: None,
+        }
+    }
+
+    pub fn with_label(mut self, label: &str) -> StreamKey {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn with_expiry(mut self, expires_at: SystemTime) -> StreamKey {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+fn verify_hmac(hmac_payload: &str, stream_key: &str, hashed_hmac_payload: &str) -> Result<(), FtlError> {
+    // * Key starts with $, omit and decode.
+    let client_hash = hashed_hmac_payload
+        .strip_prefix('$')
+        .ok_or(FtlError::DecodeError)
+        .and_then(|hash| hex::decode(hash).map_err(|_| FtlError::DecodeError))?;
+
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA512, stream_key.as_bytes());
+
+    ring::hmac::verify(
+        &key,
+        &hex::decode(hmac_payload).map_err(|_| FtlError::DecodeError)?,
+        client_hash.as_slice()
+    ).map_err(|_| FtlError::RingError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::protocol::{FtlError, FtlFeature};
+    use crate::session::{FtlLimits, FtlSession, FtlSessionEvent, StreamKey};
+
+    const HMAC_PAYLOAD: &str = "5e0c41f532c44e01b06cdb3ca5d8dc69";
+    const STREAM_KEY: &str = "ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ";
+
+    fn hash(stream_key: &str) -> String {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA512, stream_key.as_bytes());
+        let tag = ring::hmac::sign(&key, &hex::decode(HMAC_PAYLOAD).unwrap());
+        format!("${}", hex::encode(tag.as_ref()))
+    }
+
+    fn output(session: &mut FtlSession) -> String {
+        String::from_utf8(session.take_output()).unwrap()
+    }
+
+    #[test]
+    fn full_conversation() {
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string());
+
+        session.handle_input(b"HMAC\r\n\r\n");
+        assert!(session.poll_event().is_none());
+        assert_eq!(output(&mut session), format!("200 {}\n", HMAC_PAYLOAD));
+
+        session.handle_input(format!("CONNECT 77 {}\r\n\r\n", hash(STREAM_KEY)).as_bytes());
+        session.handle_input(b"ProtocolVersion: 0.9\r\n\r\nVideo: false\r\n\r\nAudio: true\r\n\r\n");
+        session.handle_input(b"AudioCodec: OPUS\r\n\r\nAudioPayloadType: 97\r\n\r\nAudioIngestSSRC: 77\r\n\r\n.\r\n\r\n");
+
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::AuthenticationRequested { channel_id }) if channel_id == "77"
+        ));
+
+        // Nothing else is processed until we provide the key.
+        assert!(session.poll_event().is_none());
+        session.authenticate(STREAM_KEY);
+        assert_eq!(output(&mut session), "200\n");
+
+        assert!(matches!(session.poll_event(), Some(FtlSessionEvent::Authenticated { .. })));
+        match session.poll_event() {
+            Some(FtlSessionEvent::HandshakeFinalised { channel_id, handshake }) => {
+                assert_eq!(channel_id, "77");
+                assert_eq!(handshake.audio.unwrap().ssrc, 77);
+                assert!(handshake.video.is_none());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        session.start_stream(65535);
+        assert!(session.is_streaming());
+        assert_eq!(output(&mut session), "200. Use UDP port 65535\n");
+
+        session.handle_input(b"PING 77\r\n\r\n");
+        assert!(matches!(session.poll_event(), Some(FtlSessionEvent::Ping { .. })));
+        assert_eq!(output(&mut session), "201\n");
+
+        session.handle_input(b"DISCONNECT\r\n\r\n");
+        assert!(matches!(session.poll_event(), Some(FtlSessionEvent::Disconnected { error: None })));
+        assert!(session.is_closed());
+        assert!(session.poll_event().is_none());
+    }
+
+    #[test]
+    fn should_negotiate_features() {
+        let supported = vec![FtlFeature::SignedPackets, FtlFeature::Roaming].into_iter().collect();
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string()).with_supported_features(supported);
+
+        session.handle_input(format!("CONNECT 77 {}\n", hash(STREAM_KEY)).as_bytes());
+        session.poll_event();
+        session.authenticate(STREAM_KEY);
+        assert!(session.packet_key().is_none());
+        session.handle_input(b"ProtocolVersion: 0.1-features\nFeatures: SignedPackets Telepathy\n.\n");
+
+        session.poll_event();
+        match session.poll_event() {
+            Some(FtlSessionEvent::HandshakeFinalised { handshake, .. }) => {
+                assert!(handshake.features.contains(&FtlFeature::SignedPackets));
+                assert!(!handshake.features.contains(&FtlFeature::Roaming));
+                assert!(!handshake.features.contains(&FtlFeature::Other("Telepathy".to_string())));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        assert!(session.packet_key().is_some());
+        session.start_stream(24000);
+        assert_eq!(output(&mut session), "200\n200. Use UDP port 24000. Features: SignedPackets\n");
+    }
+
+    #[test]
+    fn should_resume_stream() {
+        let supported = vec![FtlFeature::Roaming].into_iter().collect();
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string()).with_supported_features(supported);
+
+        session.handle_input(format!("HMAC\nRESUME 77 {}\nPING 77\n", hash(STREAM_KEY)).as_bytes());
+        assert!(matches!(session.poll_event(), Some(FtlSessionEvent::AuthenticationRequested { .. })));
+        session.authenticate(STREAM_KEY);
+
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::ResumeRequested { channel_id }) if channel_id == "77"
+        ));
+        assert!(session.poll_event().is_none());
+
+        session.resume_stream();
+        assert!(session.is_streaming());
+        assert!(matches!(session.poll_event(), Some(FtlSessionEvent::Ping { .. })));
+        assert_eq!(output(&mut session), format!("200 {}\n200\n201\n", HMAC_PAYLOAD));
+
+        // Servers which don't offer roaming don't understand RESUME.
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string());
+        session.handle_input(format!("RESUME 77 {}\n", hash(STREAM_KEY)).as_bytes());
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::Disconnected { error: Some(FtlError::UnimplementedCommand) })
+        ));
+    }
+
+    #[test]
+    fn should_reject_wrong_key() {
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string());
+        session.handle_input(format!("CONNECT 77 {}\n", hash("wrong key")).as_bytes());
+
+        assert!(matches!(session.poll_event(), Some(FtlSessionEvent::AuthenticationRequested { .. })));
+        session.authenticate(STREAM_KEY);
+
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::Disconnected { error: Some(FtlError::RingError) })
+        ));
+        assert!(session.is_closed());
+    }
+
+    #[test]
+    fn should_accept_any_unexpired_key() {
+        let yesterday = SystemTime::now() - Duration::from_secs(60 * 60 * 24);
+        let keys = [
+            StreamKey::new(STREAM_KEY).with_label("old").with_expiry(yesterday),
+            StreamKey::new("Uf3Orxx4I5qzXEsM8amlaArdUg1Buhfk").with_label("current"),
+            StreamKey::new(STREAM_KEY).with_label("co-host"),
+        ];
+
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string());
+        session.handle_input(format!("CONNECT 77 {}\n", hash(STREAM_KEY)).as_bytes());
+        session.poll_event();
+        assert_eq!(session.authenticate_keys(&keys), Some(2));
+        assert_eq!(output(&mut session), "200\n");
+
+        // Expired keys are skipped.
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string());
+        session.handle_input(format!("CONNECT 77 {}\n", hash(STREAM_KEY)).as_bytes());
+        session.poll_event();
+        assert_eq!(session.authenticate_keys(&keys[..2]), None);
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::Disconnected { error: Some(FtlError::RingError) })
+        ));
+    }
+
+    #[test]
+    fn should_require_authentication() {
+        let mut session = FtlSession::with_hmac_payload(HMAC_PAYLOAD.to_string());
+        session.handle_input(b"ProtocolVersion: 0.9\n.\n");
+
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::Disconnected { error: Some(FtlError::InvalidStreamKey) })
+        ));
+        assert_eq!(output(&mut session), "405 Invalid stream key\n");
+    }
+
+    #[test]
+    fn should_enforce_limits() {
+        let limits = FtlLimits {
+            max_line_length: 16,
+            max_attributes: 2,
+        };
+
+        let mut session = FtlSession::new().with_limits(limits.clone());
+        session.handle_input(&[b'A'; 17]);
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::Disconnected { error: Some(FtlError::LineTooLong) })
+        ));
+        assert_eq!(output(&mut session), "400 Bad Request\n");
+
+        let mut session = FtlSession::new().with_limits(limits);
+        session.handle_input(b"VendorName: a\nVendorVersion: b\nVendorBuild: c\n");
+        assert!(matches!(
+            session.poll_event(),
+            Some(FtlSessionEvent::Disconnected { error: Some(FtlError::TooManyAttributes) })
+        ));
+    }
+}
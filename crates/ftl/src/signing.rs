@@ -0,0 +1,279 @@
+//! Signed RTP packets, see the signed UDP packets proposal.
+//!
+//! Once [`FtlFeature::SignedPackets`] is negotiated both ends derive a [`PacketKey`]
+//! from the HMAC payload and the stream key. The client then adds a header extension
+//! to every RTP packet holding an HMAC of the rest of the packet, which the ingest checks
+//! before accepting the packet. A [`ReplayWindow`] catches valid packets which are sent
+//! again. RTCP packets are not signed, so ingests should drop them on signed streams.
+//!
+//! [`FtlFeature::SignedPackets`]: crate::protocol::FtlFeature::SignedPackets
+
+use std::fmt;
+
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+
+/// Profile specific identifier of the header extension, `FT` in ASCII.
+pub const EXTENSION_PROFILE: u16 = 0x4654;
+
+/// Length of the truncated HMAC-SHA256 carried in the extension.
+pub const SIGNATURE_LENGTH: usize = 16;
+
+/// Mixed into the key so it differs from the hash sent in `CONNECT`, which anyone on the path can see.
+const KEY_CONTEXT: &[u8] = b"FTL signed packets";
+
+/// How far behind the newest sequence number a packet may be and still be accepted.
+pub const REPLAY_WINDOW: u16 = 1024;
+
+const RTP_HEADER_LENGTH: usize = 12;
+const EXTENSION_BIT: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    /// Not an RTP packet, or the lengths in its header don't add up.
+    Malformed,
+    /// Packet already has a header extension, only one is allowed.
+    ExtensionInUse,
+    /// Packet has no signature extension.
+    Unsigned,
+    /// Signature does not match, the packet was forged or altered.
+    Forged,
+    /// Sequence number was already seen or is too old, see [`ReplayWindow`].
+    Replayed,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SignatureError::Malformed => "malformed RTP packet",
+            SignatureError::ExtensionInUse => "RTP packet already has a header extension",
+            SignatureError::Unsigned => "RTP packet is not signed",
+            SignatureError::Forged => "RTP packet signature does not match",
+            SignatureError::Replayed => "RTP packet was already received",
+        })
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Per session secret used to sign and verify media packets.
+#[derive(Clone)]
+pub struct PacketKey(hmac::Key);
+
+impl fmt::Debug for PacketKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PacketKey(..)")
+    }
+}
+
+impl PacketKey {
+    /// Derive the key from the hex encoded HMAC payload issued by the server and the stream key.
+    pub fn derive(hmac_payload: &str, stream_key: &str) -> Result<PacketKey, hex::FromHexError> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, stream_key.as_bytes());
+
+        let mut context = hmac::Context::with_key(&key);
+        context.update(KEY_CONTEXT);
+        context.update(&hex::decode(hmac_payload)?);
+
+        Ok(PacketKey(hmac::Key::new(hmac::HMAC_SHA256, context.sign().as_ref())))
+    }
+
+    /// Add a signature extension to an RTP packet which has none.
+    pub fn sign(&self, packet: &[u8]) -> Result<Vec<u8>, SignatureError> {
+        let header_length = header_length(packet)?;
+        if packet[0] & EXTENSION_BIT != 0 {
+            return Err(SignatureError::ExtensionInUse);
+        }
+
+        let mut signed = Vec::with_capacity(packet.len() + 4 + SIGNATURE_LENGTH);
+        signed.push(packet[0] | EXTENSION_BIT);
+        signed.extend_from_slice(&packet[1..header_length]);
+        signed.extend_from_slice(&EXTENSION_PROFILE.to_be_bytes());
+        signed.extend_from_slice(&((SIGNATURE_LENGTH / 4) as u16).to_be_bytes());
+        signed.extend_from_slice(&self.signature(packet, header_length, &packet[header_length..]));
+        signed.extend_from_slice(&packet[header_length..]);
+
+        Ok(signed)
+    }
+
+    /// Check the signature extension of an RTP packet.
+    pub fn verify(&self, packet: &[u8]) -> Result<(), SignatureError> {
+        let header_length = header_length(packet)?;
+        if packet[0] & EXTENSION_BIT == 0 {
+            return Err(SignatureError::Unsigned);
+        }
+
+        let extension = packet.get(header_length..header_length + 4).ok_or(SignatureError::Malformed)?;
+        let profile = u16::from_be_bytes([extension[0], extension[1]]);
+        let length = u16::from_be_bytes([extension[2], extension[3]]) as usize * 4;
+        if profile != EXTENSION_PROFILE || length != SIGNATURE_LENGTH {
+            return Err(SignatureError::Unsigned);
+        }
+
+        let signature = packet.get(header_length + 4..header_length + 4 + length).ok_or(SignatureError::Malformed)?;
+        let payload = &packet[header_length + 4 + length..];
+        verify_slices_are_equal(signature, &self.signature(packet, header_length, payload)).map_err(|_| SignatureError::Forged)
+    }
+
+    /// HMAC of the header, CSRCs and payload, everything but the extension.
+    ///
+    /// The extension bit is left out so the unsigned and signed packet give the same result.
+    fn signature(&self, packet: &[u8], header_length: usize, payload: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+        let mut context = hmac::Context::with_key(&self.0);
+        context.update(&[packet[0] & !EXTENSION_BIT]);
+        context.update(&packet[1..header_length]);
+        context.update(payload);
+        let tag = context.sign();
+
+        let mut signature = [0_u8; SIGNATURE_LENGTH];
+        signature.copy_from_slice(&tag.as_ref()[..SIGNATURE_LENGTH]);
+        signature
+    }
+}
+
+/// Sequence numbers seen recently on one stream, used to drop replayed packets.
+///
+/// A valid signature only shows the packet came from the client at some point, so
+/// check every verified packet's sequence number here as well. Packets may arrive out
+/// of order as long as they are within [`REPLAY_WINDOW`] of the newest one.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    /// Newest sequence number, extended past 16 bits so wrapping around is handled.
+    highest: Option<u64>,
+    seen: [u64; REPLAY_WINDOW as usize / 64],
+}
+
+impl ReplayWindow {
+    /// Record a sequence number, fails if it was already seen or is too far behind.
+    pub fn check(&mut self, sequence_number: u16) -> Result<(), SignatureError> {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                // Start far enough in that the window never reaches below zero.
+                let extended = (1 << 16) + sequence_number as u64;
+                self.highest = Some(extended);
+                self.mark(extended);
+                return Ok(());
+            }
+        };
+
+        let distance = sequence_number.wrapping_sub(highest as u16) as i16;
+        let extended = (highest as i64 + distance as i64) as u64;
+        if distance > 0 {
+            for skipped in (highest + 1..extended).take(REPLAY_WINDOW as usize) {
+                self.unmark(skipped);
+            }
+
+            self.highest = Some(extended);
+        } else if highest - extended >= REPLAY_WINDOW as u64 || self.is_marked(extended) {
+            return Err(SignatureError::Replayed);
+        }
+
+        self.mark(extended);
+        Ok(())
+    }
+
+    fn is_marked(&self, extended: u64) -> bool {
+        let bit = extended % REPLAY_WINDOW as u64;
+        self.seen[bit as usize / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn mark(&mut self, extended: u64) {
+        let bit = extended % REPLAY_WINDOW as u64;
+        self.seen[bit as usize / 64] |= 1 << (bit % 64);
+    }
+
+    fn unmark(&mut self, extended: u64) {
+        let bit = extended % REPLAY_WINDOW as u64;
+        self.seen[bit as usize / 64] &= !(1 << (bit % 64));
+    }
+}
+
+/// Length of the fixed header and CSRCs, where the extension goes.
+fn header_length(packet: &[u8]) -> Result<usize, SignatureError> {
+    if packet.len() < RTP_HEADER_LENGTH || packet[0] >> 6 != 2 {
+        return Err(SignatureError::Malformed);
+    }
+
+    let length = RTP_HEADER_LENGTH + (packet[0] & 0x0F) as usize * 4;
+    if packet.len() < length {
+        return Err(SignatureError::Malformed);
+    }
+
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signing::{PacketKey, ReplayWindow, SignatureError, REPLAY_WINDOW};
+
+    const HMAC_PAYLOAD: &str = "a4f6d1e8c0b7";
+    const STREAM_KEY: &str = "ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ";
+
+    fn packet() -> Vec<u8> {
+        let mut packet = vec![0x80, 0x80 | 97, 0x12, 0x34, 0, 0, 0x04, 0xd2, 0, 0, 0, 77];
+        packet.extend_from_slice(b"opus");
+        packet
+    }
+
+    #[test]
+    fn should_sign_and_verify() {
+        let key = PacketKey::derive(HMAC_PAYLOAD, STREAM_KEY).unwrap();
+        let signed = key.sign(&packet()).unwrap();
+
+        assert_eq!(signed.len(), packet().len() + 20);
+        assert_eq!(signed[0], 0x90);
+        assert_eq!(&signed[12..16], &[0x46, 0x54, 0, 4]);
+        assert_eq!(&signed[32..], b"opus");
+        assert_eq!(key.verify(&signed), Ok(()));
+
+        // Both ends derive the same key.
+        assert_eq!(PacketKey::derive(HMAC_PAYLOAD, STREAM_KEY).unwrap().verify(&signed), Ok(()));
+        assert_eq!(key.sign(&signed), Err(SignatureError::ExtensionInUse));
+    }
+
+    #[test]
+    fn should_reject_forged_packets() {
+        let key = PacketKey::derive(HMAC_PAYLOAD, STREAM_KEY).unwrap();
+        let signed = key.sign(&packet()).unwrap();
+
+        let mut altered = signed.clone();
+        altered[3] ^= 1;
+        assert_eq!(key.verify(&altered), Err(SignatureError::Forged));
+
+        // The payload is covered too.
+        let mut altered = signed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert_eq!(key.verify(&altered), Err(SignatureError::Forged));
+
+        let other = PacketKey::derive("a4f6d1e8c0b8", STREAM_KEY).unwrap();
+        assert_eq!(other.verify(&signed), Err(SignatureError::Forged));
+
+        assert_eq!(key.verify(&packet()), Err(SignatureError::Unsigned));
+        assert_eq!(key.verify(&signed[..20]), Err(SignatureError::Malformed));
+        assert_eq!(key.verify(&[0x80, 97]), Err(SignatureError::Malformed));
+    }
+
+    #[test]
+    fn should_reject_replayed_packets() {
+        let mut window = ReplayWindow::default();
+        assert_eq!(window.check(65534), Ok(()));
+        assert_eq!(window.check(65534), Err(SignatureError::Replayed));
+
+        // Sequence numbers wrap around, late packets are fine once.
+        assert_eq!(window.check(1), Ok(()));
+        assert_eq!(window.check(65535), Ok(()));
+        assert_eq!(window.check(0), Ok(()));
+        assert_eq!(window.check(65535), Err(SignatureError::Replayed));
+
+        // Packets from before the window are refused, even if never seen.
+        assert_eq!(window.check(1 + REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.check(1), Err(SignatureError::Replayed));
+        assert_eq!(window.check(2), Ok(()));
+
+        // Numbers skipped over can still arrive late, once.
+        assert_eq!(window.check(2 + 2 * REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.check(2 * REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.check(2 * REPLAY_WINDOW), Err(SignatureError::Replayed));
+    }
+}
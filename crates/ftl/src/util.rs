@@ -1,4 +1,4 @@
-use rand::distributions::Uniform;
+use rand::distributions::{Alphanumeric, Uniform};
 use rand::{thread_rng, Rng};
 use hex::encode;
 
@@ -13,3 +13,45 @@ pub fn generate_hmac() -> String {
 
     encode(hmac_payload.as_slice())
 }
+
+/// Hash a hex encoded HMAC payload with a stream key (HMAC-SHA512),
+/// returning the hex encoded digest sent in `CONNECT`.
+pub fn hash_hmac(hmac_payload: &str, stream_key: &str) -> Result<String, hex::FromHexError> {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA512, stream_key.as_bytes());
+    let tag = ring::hmac::sign(&key, &hex::decode(hmac_payload)?);
+
+    Ok(encode(tag.as_ref()))
+}
+
+/// Length of the stream keys encoders expect.
+pub const STREAM_KEY_LENGTH: usize = 32;
+
+/// Generate a random stream key, 32 ASCII letters and digits.
+pub fn generate_stream_key() -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(STREAM_KEY_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Whether the key has the same format as those from [`generate_stream_key`].
+pub fn is_stream_key_format(key: &str) -> bool {
+    key.len() == STREAM_KEY_LENGTH && key.bytes().all(|byte| byte.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::{generate_stream_key, is_stream_key_format};
+
+    #[test]
+    fn should_generate_stream_keys() {
+        let key = generate_stream_key();
+        assert!(is_stream_key_format(&key), "{}", key);
+        assert_ne!(key, generate_stream_key());
+
+        assert!(is_stream_key_format("ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ"));
+        assert!(!is_stream_key_format("ieDQxSZ7q58EEeLTvja4QKKGzndwUkV"));
+        assert!(!is_stream_key_format("ieDQxSZ7q58EEeLTvja4QKKGzndwUk-Q"));
+    }
+}
@@ -1,13 +1,15 @@
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::time::Duration;
 use async_std::task;
 use async_trait::async_trait;
-use ftl_protocol::protocol::FtlHandshakeFinalised;
+use ftl_protocol::protocol::{FtlFeature, FtlHandshakeFinalised};
+use hyperspeed_broadcast::ingest::socket::{FtlIngest, SourceFilter, SourceHandle};
 use hyperspeed_broadcast::rtc::workers::WorkerPool;
 use hyperspeed_broadcast::signaling::websocket::StreamInformation;
 use hyperspeed_broadcast::rtc::routers::{DataSource, HyperspeedRouter};
 
 use async_std::channel::Receiver;
-use std::sync::{RwLock};
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use once_cell::sync::OnceCell;
 
@@ -21,8 +23,14 @@ async fn main() -> std::io::Result<()> {
     let routers = RwLock::new(HashMap::<String, HyperspeedRouter>::new());
     ROUTERS.set(routers).ok();
 
-    use ftl_protocol::server::IngestServer;
-    struct MyIngestServer {}
+    use ftl_protocol::ports::PortAllocator;
+    use ftl_protocol::server::{IngestConfig, IngestServer, MediaActivity, SessionInfo};
+    use ftl_protocol::signing::PacketKey;
+    struct MyIngestServer {
+        ports: PortAllocator,
+        // Source filter of each channel's ingest, so resumed streams can be re-pinned.
+        sources: Arc<RwLock<HashMap<String, SourceHandle>>>,
+    }
 
     #[async_trait]
     impl IngestServer for MyIngestServer {
@@ -31,24 +39,52 @@ async fn main() -> std::io::Result<()> {
                 "77" => Ok("ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ".to_string()),
                 "78" => Ok("ieDQxSZ7q58EEeLTvja4QKKGzndwUkVQ".to_string()),
                 "7543" => Ok("Uf3Orxx4I5qzXEsM8amlaArdUg1Buhfk".to_string()),
-                _ => unimplemented!()
+                _ => Err(())
+            }
+        }
+
+        fn config(&self) -> IngestConfig {
+            IngestConfig {
+                media_timeout: Some(Duration::from_secs(10)),
+                supported_features: vec![FtlFeature::SignedPackets, FtlFeature::Roaming].into_iter().collect(),
+                ..IngestConfig::default()
+            }
+        }
+
+        async fn on_resume(&self, info: &SessionInfo, _previous_addr: SocketAddr) {
+            // Media now comes from the address the client resumed from.
+            if let Some(channel_id) = &info.channel_id {
+                if let Some(source) = self.sources.read().unwrap().get(channel_id) {
+                    source.set(SourceFilter::Pinned(info.peer_addr.ip()));
+                }
             }
         }
 
-        async fn allocate_ingest(&self, channel_id: &str, handshake: FtlHandshakeFinalised, stop_receiver: Receiver<()>) -> Result<u16, ()> {
-            let port = match channel_id {
-                "77" => 65534,
-                "78" => 65535,
-                "7543" => 65535,
-                _ => unimplemented!()
+        async fn allocate_ingest(&self, channel_id: &str, peer_addr: SocketAddr, handshake: FtlHandshakeFinalised, stop_receiver: Receiver<()>, activity: MediaActivity, packet_key: Option<PacketKey>) -> Result<u16, ()> {
+            // Bound before the port is handed out, and released again once the stream ends.
+            let socket = self.ports.allocate(&stop_receiver).ok_or(())?;
+            let ingest = FtlIngest::from_std(socket, SourceFilter::Pinned(peer_addr.ip()), &handshake)
+                .map_err(|_| ())?
+                .with_activity(activity);
+
+            let port = ingest.local_addr().map_err(|_| ())?.port();
+
+            let ingest = match packet_key {
+                Some(packet_key) => ingest.with_packet_key(packet_key),
+                None => ingest,
             };
 
             let channel_id = channel_id.to_string();
+            let source = ingest.source_handle();
+            self.sources.write().unwrap().insert(channel_id.clone(), source.clone());
+
+            let sources = self.sources.clone();
             task::spawn_local(async move {
+                // The router only listens locally, media reaches it through FtlIngest.
                 let router = HyperspeedRouter::new(
                     channel_id.to_string(),
-                    DataSource::Ftl(handshake),
-                    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
+                    DataSource::Ftl(handshake.clone()),
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
                 ).await;
 
                 // ! FIXME: questionable code
@@ -60,13 +96,20 @@ async fn main() -> std::io::Result<()> {
                 drop(routers);
 
                 // Launch UDP ingest server
-                stop_receiver.recv().await.unwrap();
+                ingest.run(router.get_transport_addr(), stop_receiver).await;
                 
-                // and drop it
+                // and drop it, unless a new session has already taken over the channel.
                 let routers = ROUTERS.get().unwrap();
                 let mut routers = routers.write().unwrap();
-                routers.remove(&channel_id);
+                if routers.get(&channel_id).map(|current| current.clone_router().id()) == Some(router.clone_router().id()) {
+                    routers.remove(&channel_id);
+                }
                 drop(routers);
+
+                let mut sources = sources.write().unwrap();
+                if sources.get(&channel_id).map(|current| current.ptr_eq(&source)) == Some(true) {
+                    sources.remove(&channel_id);
+                }
             });
 
             Ok(port)
@@ -94,7 +137,12 @@ async fn main() -> std::io::Result<()> {
     }
 
     task::spawn(MySignalingServer {}.launch("0.0.0.0:9050", "192.168.0.10"));
-    MyIngestServer {}.launch("0.0.0.0:8084".to_string()).await?;
+    let ingest_server: &'static MyIngestServer = Box::leak(Box::new(MyIngestServer {
+        ports: PortAllocator::default(),
+        sources: Arc::new(RwLock::new(HashMap::new())),
+    }));
+
+    ingest_server.launch("0.0.0.0:8084".to_string()).await?;
 
     Ok(())
 }